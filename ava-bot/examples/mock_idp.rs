@@ -0,0 +1,189 @@
+//! A minimal OpenID Connect provider to try the single sign-on of Ava locally.
+//!
+//! Run it with `cargo run --example mock_idp` and add to Ava's config:
+//!
+//! ```toml
+//! [oidc]
+//! issuer = "http://localhost:9000"
+//! client_id = "ava"
+//! client_secret = "secret"
+//! ```
+//!
+//! The authorize page asks for a user name and signs in as it, no password.
+
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const ADDR: &str = "127.0.0.1:9000";
+const CLIENT_ID: &str = "ava";
+const CLIENT_SECRET: &str = "secret";
+
+struct Grant {
+    username: String,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+}
+
+// authorization code -> grant, access token -> username
+static CODES: Lazy<DashMap<String, Grant>> = Lazy::new(DashMap::new);
+static TOKENS: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
+
+/// The issuer is wherever the provider is reached, so it runs on any port.
+#[handler]
+async fn discovery(req: &mut Request, res: &mut Response) {
+    let host = req.header::<String>("host").unwrap_or_else(|| ADDR.to_string());
+    let issuer = format!("http://{host}");
+    res.render(Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "userinfo_endpoint": format!("{issuer}/userinfo"),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "code_challenge_methods_supported": ["S256"],
+    })));
+}
+
+/// Without a `username` show a form asking for one, with it redirect back to
+/// the client with a code.
+#[handler]
+async fn authorize(req: &mut Request, res: &mut Response) {
+    let query = |name: &str| req.query::<String>(name).unwrap_or_default();
+    if query("client_id") != CLIENT_ID {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render("unknown client_id");
+        return;
+    }
+
+    let username = query("username");
+    if username.is_empty() {
+        let hidden: String = ["redirect_uri", "state", "code_challenge", "client_id"]
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"<input type="hidden" name="{name}" value="{}">"#,
+                    html_escape(&query(name))
+                )
+            })
+            .collect();
+        res.render(Text::Html(format!(
+            r#"<form method="get" action="/authorize">{hidden}
+<label>Sign in as <input name="username" autofocus></label> <button>Continue</button></form>"#
+        )));
+        return;
+    }
+
+    let code = Uuid::new_v4().to_string();
+    let redirect_uri = query("redirect_uri");
+    CODES.insert(
+        code.clone(),
+        Grant {
+            username,
+            redirect_uri: redirect_uri.clone(),
+            code_challenge: req.query::<String>("code_challenge"),
+        },
+    );
+    let url = match reqwest::Url::parse_with_params(
+        &redirect_uri,
+        [("code", code.as_str()), ("state", query("state").as_str())],
+    ) {
+        Ok(url) => url,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(format!("invalid redirect_uri: {e}"));
+            return;
+        }
+    };
+    res.render(Redirect::found(url.as_str()));
+}
+
+#[handler]
+async fn token(req: &mut Request, res: &mut Response) {
+    let code = req.form::<String>("code").await.unwrap_or_default();
+    let redirect_uri = req.form::<String>("redirect_uri").await.unwrap_or_default();
+    let client_secret = req
+        .form::<String>("client_secret")
+        .await
+        .unwrap_or_default();
+    let verifier = req
+        .form::<String>("code_verifier")
+        .await
+        .unwrap_or_default();
+
+    let Some((_, grant)) = CODES.remove(&code) else {
+        return token_error(res, "invalid_grant");
+    };
+    if client_secret != CLIENT_SECRET {
+        return token_error(res, "invalid_client");
+    }
+    let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    if grant.redirect_uri != redirect_uri
+        || grant
+            .code_challenge
+            .is_some_and(|expected| expected != challenge)
+    {
+        return token_error(res, "invalid_grant");
+    }
+
+    let access_token = Uuid::new_v4().to_string();
+    TOKENS.insert(access_token.clone(), grant.username);
+    res.render(Json(json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+    })));
+}
+
+#[handler]
+async fn userinfo(req: &mut Request, res: &mut Response) {
+    let token = req
+        .header::<String>("authorization")
+        .and_then(|v| v.strip_prefix("Bearer ").map(|v| v.to_string()))
+        .unwrap_or_default();
+    match TOKENS.get(&token) {
+        Some(username) => res.render(Json(json!({
+            "sub": format!("mock|{}", *username),
+            "preferred_username": *username,
+        }))),
+        None => {
+            res.status_code(StatusCode::UNAUTHORIZED);
+        }
+    }
+}
+
+fn token_error(res: &mut Response, error: &str) {
+    res.status_code(StatusCode::BAD_REQUEST);
+    res.render(Json(json!({ "error": error })));
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The provider's routes, also served by the OIDC test of Ava.
+pub fn router() -> Router {
+    Router::new()
+        .push(Router::with_path(".well-known/openid-configuration").get(discovery))
+        .push(Router::with_path("authorize").get(authorize))
+        .push(Router::with_path("token").post(token))
+        .push(Router::with_path("userinfo").get(userinfo))
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let acceptor = TcpListener::new(ADDR).bind().await;
+    Server::new(acceptor).serve(router()).await;
+}
@@ -0,0 +1,201 @@
+use crate::handlers::app_state;
+use crate::session::COOKIE_NAME;
+use crate::store::Store;
+use crate::voice::AudioFormat;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use salvo::fs::NamedFile;
+use salvo::http::StatusCode;
+use salvo::{handler, Depot, Request, Response};
+use sha2::Sha256;
+use std::path::{Component, Path, PathBuf};
+use time::OffsetDateTime;
+use tracing::warn;
+
+pub(crate) const ASSETS_DIR: &str = "./tmp/ava-bot";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Short-lived tokens letting anyone holding an asset url fetch the asset,
+/// e.g. API and MCP clients which have no device session.
+pub struct AssetTokens {
+    key: Vec<u8>,
+    /// seconds a token stays valid
+    ttl: i64,
+}
+
+impl AssetTokens {
+    pub fn new(key: impl Into<Vec<u8>>, ttl: u64) -> Self {
+        Self {
+            key: key.into(),
+            ttl: ttl as i64,
+        }
+    }
+
+    /// Token for `path` (relative to the assets dir), of the form `{expires}.{signature}`.
+    fn issue(&self, path: &str) -> String {
+        let expires = OffsetDateTime::now_utc().unix_timestamp() + self.ttl;
+        let signature = self.mac(path, expires).finalize().into_bytes();
+        format!("{expires}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    pub(crate) fn verify(&self, path: &str, token: &str) -> bool {
+        let Some((expires, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let (Ok(expires), Ok(signature)) = (
+            expires.parse::<i64>(),
+            BASE64_URL_SAFE_NO_PAD.decode(signature),
+        ) else {
+            return false;
+        };
+        expires >= OffsetDateTime::now_utc().unix_timestamp()
+            && self.mac(path, expires).verify_slice(&signature).is_ok()
+    }
+
+    /// Url of the asset at `path` (relative to the assets dir), carrying a token.
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("./assets/{path}?token={}", self.issue(path))
+    }
+
+    fn mac(&self, path: &str, expires: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("hmac accepts any key size");
+        mac.update(format!("asset:{path}:{expires}").as_bytes());
+        mac
+    }
+}
+
+/// Path of an asset below the assets dir, refusing anything that escapes it.
+pub(crate) fn asset_path(name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    name.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| Path::new(ASSETS_DIR).join(name))
+}
+
+/// Mime type of an asset: speech in one of the audio formats, or an image.
+/// Guessing from the extension gets opus wrong, it's ogg.
+pub(crate) fn asset_mime(name: &str) -> Option<&'static str> {
+    let ext = name.rsplit_once('.')?.1;
+    match ext {
+        "png" => Some("image/png"),
+        _ => AudioFormat::from_extension(ext).map(AudioFormat::mime),
+    }
+}
+
+/// Whether `device_id` may read the assets of `owner`: its own, those of the
+/// user it's signed in as, and those other devices of the user made before
+/// they signed in.
+fn owns(store: &Store, device_id: &str, owner: &str) -> bool {
+    if device_id == owner {
+        return true;
+    }
+    let user_of = |id: &str| store.device_user(id).ok().flatten().map(|user| user.id);
+    match user_of(device_id) {
+        Some(user_id) => user_id == owner || user_of(owner).as_ref() == Some(&user_id),
+        None => false,
+    }
+}
+
+/// Serve `/assets/{kind}/{device_id}/{file}` to the device owning it, or to
+/// anyone with a valid token. Directories are never listed.
+#[handler]
+pub async fn assets_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let name = req.param::<String>("path").unwrap_or_default();
+    let Some(path) = asset_path(&name).filter(|path| path.is_file()) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+
+    let owner = name.split('/').nth(1).unwrap_or_default();
+    let Ok(state) = app_state(depot) else {
+        res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        return;
+    };
+    let is_owner = req
+        .cookie(COOKIE_NAME)
+        .and_then(|cookie| state.sessions.verify(cookie.value()))
+        .is_some_and(|device_id| owns(&state.store, &device_id, owner));
+    let has_token = req
+        .query::<String>("token")
+        .is_some_and(|token| state.assets.verify(&name, &token));
+    if !(is_owner || has_token) {
+        warn!("reject access to asset {name}");
+        res.status_code(StatusCode::FORBIDDEN);
+        return;
+    }
+
+    let mut file = NamedFile::builder(&path);
+    if let Some(mime) = asset_mime(&name) {
+        file = file.content_type(mime.parse().expect("asset mime types are valid"));
+    }
+    file.send(req.headers(), res).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "audio/6f2b3c1e-8a4d-4c2f-9b1e-3d5a7c9e0f12/reply.mp3";
+
+    fn token_expiring(tokens: &AssetTokens, path: &str, expires: i64) -> String {
+        let signature = tokens.mac(path, expires).finalize().into_bytes();
+        format!("{expires}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn issued_token_verifies_for_its_path_only() {
+        let tokens = AssetTokens::new("secret", 60);
+        let token = tokens.issue(PATH);
+        assert!(tokens.verify(PATH, &token));
+        assert!(!tokens.verify("audio/other/reply.mp3", &token));
+        assert!(!AssetTokens::new("other", 60).verify(PATH, &token));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let tokens = AssetTokens::new("secret", 60);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        assert!(!tokens.verify(PATH, &token_expiring(&tokens, PATH, now - 1)));
+        // the expiry is signed, pushing it out breaks the signature
+        let token = tokens.issue(PATH);
+        let (_, signature) = token.split_once('.').unwrap();
+        assert!(!tokens.verify(PATH, &format!("{}.{signature}", now + 3600)));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let tokens = AssetTokens::new("secret", 60);
+        let token = tokens.issue(PATH);
+        let (expires, _) = token.split_once('.').unwrap();
+        assert!(!tokens.verify(PATH, ""));
+        assert!(!tokens.verify(PATH, expires));
+        assert!(!tokens.verify(PATH, &format!("{expires}.")));
+        assert!(!tokens.verify(PATH, &token[..token.len() - 2]));
+        let signature = &token[expires.len() + 1..];
+        assert!(!tokens.verify(PATH, &format!("soon.{signature}")));
+    }
+
+    #[test]
+    fn asset_path_stays_below_the_assets_dir() {
+        assert_eq!(asset_path(PATH), Some(Path::new(ASSETS_DIR).join(PATH)));
+        assert_eq!(asset_path("../ava-bot.db"), None);
+        assert_eq!(asset_path("audio/../../ava-bot.db"), None);
+        assert_eq!(asset_path("/etc/passwd"), None);
+    }
+
+    #[test]
+    fn devices_own_assets_of_their_user() {
+        let store = Store::in_memory().unwrap();
+        let user = store.create_user(None, "Guest", None).unwrap();
+        store.link_device("phone", &user.id, "").unwrap();
+        store.link_device("laptop", &user.id, "").unwrap();
+
+        assert!(owns(&store, "phone", "phone"));
+        assert!(owns(&store, "phone", &user.id));
+        assert!(owns(&store, "phone", "laptop"));
+        assert!(!owns(&store, "phone", "stranger"));
+        assert!(!owns(&store, "stranger", &user.id));
+    }
+}
@@ -0,0 +1,562 @@
+use crate::session::load_or_create_key;
+use crate::voice::{AudioFormat, Voice, VoiceModel, MAX_SPEED, MIN_SPEED};
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use llm_sdk::ChatCompleteModel;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Runtime configuration. Values are taken from the defaults below, then the
+/// TOML config file, then env vars / command line flags (see `Args`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub llm: LlmConfig,
+    pub speech: SpeechConfig,
+    pub whisper: WhisperConfig,
+    pub prompts: PromptConfig,
+    pub memory: MemoryConfig,
+    pub agent: AgentConfig,
+    pub mcp: McpConfig,
+    pub api: ApiConfig,
+    pub session: SessionConfig,
+    pub assets: AssetsConfig,
+    pub events: EventsConfig,
+    /// sign in through an OpenID Connect provider, next to passwords
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// directory holding cert.pem and key.pem, served over https when present
+    pub cert_path: String,
+    /// plain http port redirecting to https
+    pub redirect_port: Option<u16>,
+    /// path of the sqlite database keeping chat history
+    pub db_path: String,
+    /// url Ava is reached at, used for pairing links and links to generated
+    /// assets; defaults to `http://localhost:{port}`
+    pub public_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    /// base url of the OpenAI compatible API
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub model: ChatCompleteModel,
+    /// providers tried in order once this one keeps failing
+    pub fallbacks: Vec<FallbackConfig>,
+    pub retry: RetryConfig,
+    pub timeouts: TimeoutConfig,
+}
+
+/// Another OpenAI compatible provider, or another model of the same one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackConfig {
+    pub base_url: String,
+    /// defaults to `llm.api_key`
+    pub api_key: Option<String>,
+    /// chat completion model, defaults to `llm.model`
+    pub model: Option<ChatCompleteModel>,
+}
+
+/// How failed calls to a provider are retried before falling back. Only
+/// timeouts, rate limits, 5xx and connection errors are retried.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// calls per provider, including the first one
+    pub max_attempts: usize,
+    /// delay before the first retry, doubled for every following one
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+/// Seconds a call to a provider may take.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub transcription: u64,
+    /// until the reply; when streamed, until the first delta and between
+    /// any two deltas after
+    pub chat: u64,
+    pub speech: u64,
+    pub image: u64,
+}
+
+/// Voice settings of devices that didn't change theirs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeechConfig {
+    pub voice: Voice,
+    /// 0.25 to 4.0, 1.0 is the normal speed
+    pub speed: f32,
+    pub model: VoiceModel,
+    /// used when neither the device nor the client's `Accept` header picks one
+    pub format: AudioFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WhisperConfig {
+    pub prompt: String,
+}
+
+/// System prompts of the completions Ava makes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptConfig {
+    /// picks the tool to handle user's input
+    pub tool: String,
+    pub answer: String,
+    pub write_code: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// max number of past turns kept as conversation memory per device
+    pub max_turns: usize,
+    /// max estimated tokens of conversation memory per device
+    pub max_tokens: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// max rounds of tool calls before giving up on a turn
+    pub max_steps: usize,
+    /// assistant requests processed at once, later ones wait in the queue
+    pub max_jobs: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct McpConfig {
+    /// MCP servers whose tools are offered to the model
+    pub servers: Vec<McpServerConfig>,
+    /// serve Ava's own tools over streamable HTTP at `/mcp`, to clients with
+    /// a bearer token of `api.keys`
+    pub http: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// bearer tokens accepted by the HTTP API routes, which are only served
+    /// when at least one is set
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// key signing the device cookies, read from (or created at) `key_path` when unset
+    pub secret: Option<String>,
+    pub key_path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssetsConfig {
+    /// seconds the token in an audio / image url stays valid for clients
+    /// other than the owning device
+    pub token_ttl: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// seconds the events of a user without open event streams are kept
+    /// after the last one, to be replayed when the browser reconnects
+    pub idle_ttl: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// e.g. http://localhost:9000 for the mock provider in examples/mock_idp.rs
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// defaults to `{public_url}/auth/oidc/callback`
+    pub redirect_url: Option<String>,
+    #[serde(default = "OidcConfig::default_scopes")]
+    pub scopes: String,
+    /// label of the sign in button
+    #[serde(default = "OidcConfig::default_label")]
+    pub label: String,
+}
+
+/// An MCP server reached either by spawning `command` (stdio) or at `url`
+/// (streamable HTTP).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct McpServerConfig {
+    /// prefix of the server's tool names, e.g. "crm" for "crm__search"
+    pub name: String,
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub url: Option<String>,
+    /// extra http headers, e.g. authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// seconds a request to the server, e.g. a tool call, may take
+    #[serde(default = "McpServerConfig::default_timeout")]
+    pub timeout: u64,
+}
+
+impl Config {
+    /// Load the config file (if any) and apply `args` on top of it.
+    pub fn load(args: &Args) -> Result<Self> {
+        let path = Path::new(&args.config);
+        let mut config = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read config file {}", path.display()))?;
+            toml::from_str(&content)
+                .with_context(|| format!("failed to parse config file {}", path.display()))?
+        } else if args.config != Args::DEFAULT_CONFIG {
+            bail!("config file {} not found", path.display());
+        } else {
+            Config::default()
+        };
+
+        config.merge(args)?;
+        if config.session.secret.is_none() {
+            config.session.secret = Some(load_or_create_key(&config.session.key_path)?);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn api_key(&self) -> &str {
+        self.llm.api_key.as_deref().unwrap_or_default()
+    }
+
+    pub fn session_secret(&self) -> &str {
+        self.session.secret.as_deref().unwrap_or_default()
+    }
+
+    pub fn oidc_redirect_url(&self) -> String {
+        match self
+            .oidc
+            .as_ref()
+            .and_then(|oidc| oidc.redirect_url.clone())
+        {
+            Some(url) => url,
+            None => format!("{}/auth/oidc/callback", self.public_url()),
+        }
+    }
+
+    pub fn public_url(&self) -> String {
+        match &self.server.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://localhost:{}", self.server.port),
+        }
+    }
+
+    fn merge(&mut self, args: &Args) -> Result<()> {
+        if let Some(v) = args.port {
+            self.server.port = v;
+        }
+        if let Some(v) = &args.cert_path {
+            self.server.cert_path = v.clone();
+        }
+        if let Some(v) = args.redirect_port {
+            self.server.redirect_port = Some(v);
+        }
+        if let Some(v) = &args.db_path {
+            self.server.db_path = v.clone();
+        }
+        if let Some(v) = &args.public_url {
+            self.server.public_url = Some(v.clone());
+        }
+        if let Some(v) = &args.base_url {
+            self.llm.base_url = v.clone();
+        }
+        if let Some(v) = &args.api_key {
+            self.llm.api_key = Some(v.clone());
+        }
+        if let Some(v) = &args.model {
+            self.llm.model = parse_value("model", v)?;
+        }
+        if let Some(v) = &args.voice {
+            self.speech.voice = parse_value("voice", v)?;
+        }
+        if let Some(v) = &args.whisper_prompt {
+            self.whisper.prompt = v.clone();
+        }
+        if let Some(v) = &args.prompt_tool {
+            self.prompts.tool = v.clone();
+        }
+        if let Some(v) = &args.prompt_answer {
+            self.prompts.answer = v.clone();
+        }
+        if let Some(v) = &args.prompt_write_code {
+            self.prompts.write_code = v.clone();
+        }
+        if let Some(v) = args.max_turns {
+            self.memory.max_turns = v;
+        }
+        if let Some(v) = args.max_tokens {
+            self.memory.max_tokens = v;
+        }
+        if let Some(v) = &args.session_secret {
+            self.session.secret = Some(v.clone());
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.server.port == 0 {
+            bail!("server.port must not be 0");
+        }
+        if self.server.redirect_port == Some(self.server.port) {
+            bail!("server.redirect_port must differ from server.port");
+        }
+        if self.server.db_path.trim().is_empty() {
+            bail!("server.db_path must not be empty");
+        }
+        if !(self.llm.base_url.starts_with("http://") || self.llm.base_url.starts_with("https://"))
+        {
+            bail!(
+                "llm.base_url must be an http(s) url, got {:?}",
+                self.llm.base_url
+            );
+        }
+        if self.api_key().trim().is_empty() {
+            bail!("llm.api_key is not set, use the config file, OPENAI_API_KEY or --api-key");
+        }
+        for (i, fallback) in self.llm.fallbacks.iter().enumerate() {
+            if !(fallback.base_url.starts_with("http://")
+                || fallback.base_url.starts_with("https://"))
+            {
+                bail!(
+                    "llm.fallbacks[{i}].base_url must be an http(s) url, got {:?}",
+                    fallback.base_url
+                );
+            }
+        }
+        if self.llm.retry.max_attempts == 0 {
+            bail!("llm.retry.max_attempts must be at least 1");
+        }
+        if self.llm.retry.max_backoff_ms < self.llm.retry.initial_backoff_ms {
+            bail!("llm.retry.max_backoff_ms must not be less than llm.retry.initial_backoff_ms");
+        }
+        for (name, secs) in [
+            ("transcription", self.llm.timeouts.transcription),
+            ("chat", self.llm.timeouts.chat),
+            ("speech", self.llm.timeouts.speech),
+            ("image", self.llm.timeouts.image),
+        ] {
+            if secs == 0 {
+                bail!("llm.timeouts.{name} must be at least 1");
+            }
+        }
+        if !(MIN_SPEED..=MAX_SPEED).contains(&self.speech.speed) {
+            bail!("speech.speed must be between {MIN_SPEED} and {MAX_SPEED}");
+        }
+        if self.agent.max_steps == 0 {
+            bail!("agent.max_steps must be at least 1");
+        }
+        if self.agent.max_jobs == 0 {
+            bail!("agent.max_jobs must be at least 1");
+        }
+        for (name, prompt) in [
+            ("prompts.tool", &self.prompts.tool),
+            ("prompts.answer", &self.prompts.answer),
+            ("prompts.write_code", &self.prompts.write_code),
+        ] {
+            if prompt.trim().is_empty() {
+                bail!("{name} must not be empty");
+            }
+        }
+        if self.session_secret().len() < 16 {
+            bail!("session.secret must be at least 16 characters");
+        }
+        if self.assets.token_ttl == 0 {
+            bail!("assets.token_ttl must be at least 1");
+        }
+        if self.events.idle_ttl == 0 {
+            bail!("events.idle_ttl must be at least 1");
+        }
+        if let Some(oidc) = &self.oidc {
+            if !(oidc.issuer.starts_with("http://") || oidc.issuer.starts_with("https://")) {
+                bail!("oidc.issuer must be an http(s) url, got {:?}", oidc.issuer);
+            }
+            if oidc.client_id.is_empty() {
+                bail!("oidc.client_id must not be empty");
+            }
+        }
+        if self.api.keys.iter().any(|k| k.trim().is_empty()) {
+            bail!("api.keys must not contain empty keys");
+        }
+        for (i, server) in self.mcp.servers.iter().enumerate() {
+            if server.name.is_empty()
+                || !server
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                bail!("mcp.servers[{i}].name must be non-empty ascii letters, digits or '-'");
+            }
+            if self.mcp.servers[..i].iter().any(|s| s.name == server.name) {
+                bail!("mcp.servers[{i}].name {} is used twice", server.name);
+            }
+            if server.command.is_some() == server.url.is_some() {
+                bail!("mcp.servers[{i}] needs either a command or a url");
+            }
+            if server.timeout == 0 {
+                bail!("mcp.servers[{i}].timeout must be at least 1");
+            }
+        }
+        Ok(())
+    }
+}
+
+// model and voice names are the same strings the API uses, e.g. "alloy"
+fn parse_value<T: DeserializeOwned>(name: &str, value: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(value.to_string()))
+        .with_context(|| format!("invalid {name}: {value}"))
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 8080,
+            cert_path: ".certs".to_string(),
+            redirect_port: None,
+            db_path: "tmp/ava-bot.db".to_string(),
+            public_url: None,
+        }
+    }
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.xty.app/v1".to_string(),
+            api_key: None,
+            model: ChatCompleteModel::default(),
+            fallbacks: vec![],
+            retry: RetryConfig::default(),
+            timeouts: TimeoutConfig::default(),
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 8000,
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            transcription: 60,
+            chat: 60,
+            speech: 60,
+            image: 120,
+        }
+    }
+}
+
+impl Default for SpeechConfig {
+    fn default() -> Self {
+        Self {
+            voice: Voice::Alloy,
+            speed: 1.0,
+            model: VoiceModel::Standard,
+            format: AudioFormat::Mp3,
+        }
+    }
+}
+
+impl Default for WhisperConfig {
+    fn default() -> Self {
+        Self {
+            prompt: "If audio language is Chinese, please use Simplified Chinese".to_string(),
+        }
+    }
+}
+
+impl Default for PromptConfig {
+    fn default() -> Self {
+        Self {
+            tool: "I can help to identify which tool to use, if no proper tool could be used, I'll directly reply the message with pure text".to_string(),
+            answer: "I can help answer anything you'd like to chat".to_string(),
+            write_code: "I'm an expert on coding, I'll write code for you in markdown format based on your prompt".to_string(),
+        }
+    }
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            max_turns: 10,
+            max_tokens: 4096,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            key_path: "tmp/session.key".to_string(),
+        }
+    }
+}
+
+impl OidcConfig {
+    fn default_scopes() -> String {
+        "openid profile email".to_string()
+    }
+
+    fn default_label() -> String {
+        "Single sign-on".to_string()
+    }
+}
+
+impl McpServerConfig {
+    fn default_timeout() -> u64 {
+        60
+    }
+}
+
+impl Default for AssetsConfig {
+    fn default() -> Self {
+        Self { token_ttl: 3600 }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self { idle_ttl: 600 }
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            max_steps: 5,
+            max_jobs: 4,
+        }
+    }
+}
@@ -1,15 +1,75 @@
+use crate::llm::UpstreamError;
 use async_trait::async_trait;
 use salvo::http::StatusCode;
+use salvo::prelude::Json;
 use salvo::{Depot, Request, Response, Writer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use strum::{AsRefStr, Display, EnumString};
+use tracing::warn;
 
 pub struct AppError(anyhow::Error);
 
+/// What went wrong, as far as clients care. `code` is the stable name
+/// clients match on, e.g. to retry on `rate_limited`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsRefStr, Display, EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum ErrorKind {
+    BadInput,
+    Unauthorized,
+    NotFound,
+    UpstreamTimeout,
+    RateLimited,
+    ContentFilter,
+    Internal,
+}
+
+/// An error of a known kind, raised with `ErrorKind::error` and carried in
+/// `anyhow` errors like any other.
+#[derive(Debug)]
+struct KindError {
+    kind: ErrorKind,
+    message: String,
+}
+
+/// Language of the messages shown to the user, from `Accept-Language`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Lang {
+    #[default]
+    En,
+    Zh,
+}
+
+/// An error as sent to clients, in a json body or an error event. What
+/// actually failed is only logged, it may tell internals.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ErrorInfo {
+    pub(crate) code: ErrorKind,
+    /// localized, for the user
+    pub(crate) message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: ErrorInfo,
+}
+
 #[async_trait]
 impl Writer for AppError {
-    async fn write(mut self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
-        res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-        let err_msg = format!("Something went wrong:{}", self.0);
-        res.render(err_msg);
+    async fn write(mut self, req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        let info = ErrorInfo::new(&self.0, Lang::from_request(req));
+        warn!(
+            "{} {} failed ({}): {:#}",
+            req.method(),
+            req.uri().path(),
+            info.code,
+            self.0
+        );
+        res.status_code(info.code.status());
+        res.render(Json(ErrorBody { error: info }));
     }
 }
 
@@ -21,3 +81,168 @@ where
         Self(err.into())
     }
 }
+
+impl ErrorKind {
+    pub(crate) fn error(self, message: impl Into<String>) -> anyhow::Error {
+        KindError {
+            kind: self,
+            message: message.into(),
+        }
+        .into()
+    }
+
+    /// The kind of `err`: the one it was raised with, else the one of the
+    /// failed upstream call in its chain.
+    pub(crate) fn of(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<KindError>() {
+                return e.kind;
+            }
+            if let Some(e) = cause.downcast_ref::<UpstreamError>() {
+                return e.kind();
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                if e.is_timeout() {
+                    return Self::UpstreamTimeout;
+                }
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return Self::UpstreamTimeout;
+            }
+        }
+        Self::Internal
+    }
+
+    pub(crate) fn status(self) -> StatusCode {
+        match self {
+            Self::BadInput => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::ContentFilter => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub(crate) fn message(self, lang: Lang) -> &'static str {
+        match (self, lang) {
+            (Self::BadInput, Lang::En) => "The request is invalid",
+            (Self::BadInput, Lang::Zh) => "请求无效",
+            (Self::Unauthorized, Lang::En) => "You are not signed in, or the credentials are wrong",
+            (Self::Unauthorized, Lang::Zh) => "未登录或凭据错误",
+            (Self::NotFound, Lang::En) => "The requested item doesn't exist",
+            (Self::NotFound, Lang::Zh) => "请求的内容不存在",
+            (Self::UpstreamTimeout, Lang::En) => {
+                "The AI service took too long to answer, please try again"
+            }
+            (Self::UpstreamTimeout, Lang::Zh) => "AI 服务响应超时，请重试",
+            (Self::RateLimited, Lang::En) => "The AI service is busy, please try again in a moment",
+            (Self::RateLimited, Lang::Zh) => "AI 服务繁忙，请稍后再试",
+            (Self::ContentFilter, Lang::En) => "The request was blocked by the content filter",
+            (Self::ContentFilter, Lang::Zh) => "请求被内容过滤拦截",
+            (Self::Internal, Lang::En) => "Something went wrong",
+            (Self::Internal, Lang::Zh) => "出错了",
+        }
+    }
+}
+
+impl ErrorInfo {
+    /// The message an error was raised with tells the user what to fix, it's
+    /// only replaced by the localized one of its kind when there is none, or
+    /// it may tell internals.
+    pub(crate) fn new(err: &anyhow::Error, lang: Lang) -> Self {
+        let raised = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<KindError>());
+        let code = ErrorKind::of(err);
+        let message = match raised {
+            Some(e) if code != ErrorKind::Internal => e.message.clone(),
+            _ => code.message(lang).to_string(),
+        };
+        Self { code, message }
+    }
+}
+
+impl Lang {
+    pub(crate) fn from_request(req: &Request) -> Self {
+        let accept = req.header::<String>("accept-language").unwrap_or_default();
+        // the first listed language is the preferred one
+        match accept.split(',').next() {
+            Some(lang) if lang.trim().to_ascii_lowercase().starts_with("zh") => Self::Zh,
+            _ => Self::En,
+        }
+    }
+}
+
+impl fmt::Display for KindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for KindError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use reqwest::StatusCode as Status;
+
+    fn upstream(status: u16, body: &str) -> anyhow::Error {
+        UpstreamError::new(Status::from_u16(status).unwrap(), body).into()
+    }
+
+    #[test]
+    fn upstream_errors_map_to_kinds() {
+        let policy = r#"{"error": {"message": "nope", "type": "invalid_request_error", "code": "content_policy_violation"}}"#;
+        assert_eq!(
+            ErrorKind::of(&upstream(400, policy)),
+            ErrorKind::ContentFilter
+        );
+        assert_eq!(
+            ErrorKind::of(&upstream(429, "slow down")),
+            ErrorKind::RateLimited
+        );
+        assert_eq!(ErrorKind::of(&upstream(400, "{}")), ErrorKind::BadInput);
+        assert_eq!(ErrorKind::of(&upstream(413, "")), ErrorKind::BadInput);
+        // the key or the model is wrong, not the request
+        assert_eq!(ErrorKind::of(&upstream(401, "")), ErrorKind::Internal);
+        assert_eq!(ErrorKind::of(&upstream(404, "")), ErrorKind::Internal);
+        assert_eq!(ErrorKind::of(&upstream(503, "")), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn kind_is_found_below_context() {
+        let err = Err::<(), _>(upstream(429, ""))
+            .context("chat failed")
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::RateLimited);
+        let err = Err::<(), _>(ErrorKind::NotFound.error("job not found"))
+            .context("cancel failed")
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::of(&anyhow::anyhow!("boom")), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn error_info_tells_the_raised_message() {
+        let err = ErrorKind::BadInput.error("speed must be a number");
+        let info = ErrorInfo::new(&err, Lang::Zh);
+        assert_eq!(info.code, ErrorKind::BadInput);
+        assert_eq!(info.message, "speed must be a number");
+
+        // internals stay in the log
+        let err = ErrorKind::Internal.error("database is locked");
+        assert_eq!(
+            ErrorInfo::new(&err, Lang::En).message,
+            "Something went wrong"
+        );
+        let err = anyhow::anyhow!("database is locked");
+        assert_eq!(ErrorInfo::new(&err, Lang::Zh).message, "出错了");
+
+        // upstream failures have no message for the user, theirs is localized
+        let info = ErrorInfo::new(&upstream(429, "slow down"), Lang::Zh);
+        assert_eq!(info.message, ErrorKind::RateLimited.message(Lang::Zh));
+    }
+}
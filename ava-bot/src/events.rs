@@ -0,0 +1,219 @@
+use crate::handlers::AssistantEvent;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Events kept per channel for clients reconnecting with `Last-Event-ID`.
+const REPLAY_EVENTS: usize = 512;
+const MAX_REVOCATIONS: usize = 16;
+// capacity of the broadcast channel, a slower subscriber reconnects
+const MAX_EVENTS: usize = 128;
+
+/// The event channels of all owners. A channel lives while an event stream
+/// or a job uses it, and `idle_ttl` after its last event so reconnecting
+/// streams still get replayed what they missed.
+pub struct EventHub {
+    channels: DashMap<String, Arc<EventChannel>>,
+    idle_ttl: Duration,
+}
+
+/// Counts of the event channels, for monitoring.
+#[derive(Debug, Serialize)]
+pub struct EventStats {
+    /// channels in memory, connected or not
+    pub channels: usize,
+    /// users (or anonymous devices) with at least one open event stream
+    pub connected_owners: usize,
+    /// open event streams, one per browser tab
+    pub connections: usize,
+}
+
+/// The events of one owner, numbered in the order they're sent. The latest
+/// ones are kept so a reconnecting event stream gets what it missed.
+pub(crate) struct EventChannel {
+    tx: broadcast::Sender<SequencedEvent>,
+    // ids of devices signed out of the owner, whose streams must end
+    revocations: broadcast::Sender<String>,
+    // ids restart with every channel, e.g. after a restart, `epoch` tells
+    // them apart
+    epoch: i64,
+    state: Mutex<ReplayBuffer>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SequencedEvent {
+    /// `{epoch}.{seq}`, sent as the SSE event id
+    pub(crate) id: String,
+    pub(crate) event: AssistantEvent,
+}
+
+struct ReplayBuffer {
+    next_seq: u64,
+    events: VecDeque<(u64, SequencedEvent)>,
+    last_active: Instant,
+}
+
+impl EventHub {
+    pub(crate) fn new(idle_ttl: Duration) -> Self {
+        Self {
+            channels: DashMap::new(),
+            idle_ttl,
+        }
+    }
+
+    /// The channel of the owner's events, created by whoever needs it first:
+    /// the event stream or a job.
+    pub(crate) fn channel(&self, owner_id: &str) -> Arc<EventChannel> {
+        self.channels
+            .entry(owner_id.to_string())
+            .or_insert_with(|| Arc::new(EventChannel::new(MAX_EVENTS)))
+            .clone()
+    }
+
+    /// End the event streams `device_id` has open on the owner's channel,
+    /// e.g. once it's signed out of the user.
+    pub(crate) fn revoke(&self, owner_id: &str, device_id: &str) {
+        if let Some(channel) = self.channels.get(owner_id) {
+            channel.revoke(device_id);
+        }
+    }
+
+    /// Drop channels nobody listens on, no job sends to, and that had no
+    /// event for `idle_ttl`. Returns how many were dropped.
+    pub fn evict_idle(&self) -> usize {
+        let ttl = self.idle_ttl;
+        let before = self.channels.len();
+        // the map holds the only reference unless a job runs
+        self.channels.retain(|_, channel| {
+            Arc::strong_count(channel) > 1 || channel.subscribers() > 0 || channel.idle_for() < ttl
+        });
+        before - self.channels.len()
+    }
+
+    /// Evict idle channels every half `idle_ttl`, forever.
+    pub async fn run_eviction(&self) {
+        loop {
+            tokio::time::sleep((self.idle_ttl / 2).max(Duration::from_secs(1))).await;
+            let evicted = self.evict_idle();
+            if evicted > 0 {
+                let stats = self.stats();
+                info!(
+                    "evicted {evicted} idle event channels, {} left, {} connections",
+                    stats.channels, stats.connections
+                );
+            }
+        }
+    }
+
+    pub fn stats(&self) -> EventStats {
+        let mut stats = EventStats {
+            channels: 0,
+            connected_owners: 0,
+            connections: 0,
+        };
+        for channel in self.channels.iter() {
+            let subscribers = channel.subscribers();
+            stats.channels += 1;
+            stats.connected_owners += usize::from(subscribers > 0);
+            stats.connections += subscribers;
+        }
+        stats
+    }
+}
+
+impl EventChannel {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        let (revocations, _) = broadcast::channel(MAX_REVOCATIONS);
+        Self {
+            tx,
+            revocations,
+            epoch: OffsetDateTime::now_utc().unix_timestamp_nanos() as i64,
+            state: Mutex::new(ReplayBuffer {
+                next_seq: 1,
+                events: VecDeque::with_capacity(REPLAY_EVENTS),
+                last_active: Instant::now(),
+            }),
+        }
+    }
+
+    /// Send to every subscriber, nobody listening is fine: the event is
+    /// still kept for replay.
+    pub(crate) fn send(&self, event: AssistantEvent) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.last_active = Instant::now();
+        let event = SequencedEvent {
+            id: format!("{}.{seq}", self.epoch),
+            event,
+        };
+        if state.events.len() == REPLAY_EVENTS {
+            state.events.pop_front();
+        }
+        state.events.push_back((seq, event.clone()));
+        // under the lock, so `subscribe` sees each event either replayed or live
+        let _ = self.tx.send(event);
+    }
+
+    /// Events after `last_event_id` still in the buffer, and a receiver of
+    /// all following ones. Ids of another epoch get the whole buffer.
+    pub(crate) fn subscribe(
+        &self,
+        last_event_id: Option<&str>,
+    ) -> (Vec<SequencedEvent>, broadcast::Receiver<SequencedEvent>) {
+        let mut state = self.state.lock().unwrap();
+        state.last_active = Instant::now();
+        let rx = self.tx.subscribe();
+        let after = match last_event_id.and_then(|id| id.split_once('.')) {
+            Some((epoch, seq)) if epoch == self.epoch.to_string() => seq.parse().ok(),
+            _ => None,
+        };
+        let missed = match (last_event_id, after) {
+            // a fresh page loads the history from the store
+            (None, _) => vec![],
+            (Some(_), after) => state
+                .events
+                .iter()
+                .filter(|(seq, _)| *seq > after.unwrap_or(0))
+                .map(|(_, event)| event.clone())
+                .collect(),
+        };
+        (missed, rx)
+    }
+
+    /// End the streams `device_id` has open on the channel.
+    pub(crate) fn revoke(&self, device_id: &str) {
+        let _ = self.revocations.send(device_id.to_string());
+    }
+
+    /// Resolves once `device_id` is revoked after this call. Missed
+    /// revocations count too, the stream ends and the reconnect decides.
+    pub(crate) fn revoked(&self, device_id: &str) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.revocations.subscribe();
+        let device_id = device_id.to_string();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(id) if id != device_id => continue,
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    /// Open event streams of the channel.
+    pub(crate) fn subscribers(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.state.lock().unwrap().last_active.elapsed()
+    }
+}
@@ -0,0 +1,300 @@
+use super::{chat_completion_with_tools, retrying, SignalEvent};
+use crate::events::EventChannel;
+use crate::llm::Operation;
+use crate::memory::Turn;
+use crate::AppState;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use llm_sdk::{
+    AssistantMessage, ChatCompletionChoice, ChatCompletionMessage, ChatCompletionRequest,
+    FinishReason, ToolCall,
+};
+use tracing::{info, warn};
+
+/// Continuations asked for a reply cut off at the token limit, after that
+/// the reply ends where it's cut off.
+pub(super) const MAX_CONTINUATIONS: usize = 3;
+const CONTINUE_PROMPT: &str = "Continue exactly where you stopped, without repeating anything.";
+
+/// Where a turn goes: the event stream of the page, or the text of an API
+/// completion.
+#[async_trait]
+pub(crate) trait AgentSink: Sync {
+    /// Progress signals of the turn, retries of the model included.
+    fn signals(&self) -> &EventChannel;
+
+    /// Deliver the answer the model gave itself, `filtered` if the content
+    /// filter cut it short. Returns the reply to remember.
+    async fn answer(&self, text: String, filtered: bool) -> anyhow::Result<String>;
+
+    /// Run the tool calls of a step, with their outputs in the same order.
+    async fn call_tools(&self, calls: &[ToolCall]) -> Vec<anyhow::Result<ToolOutput>>;
+}
+
+/// What a tool call returned: `content` is fed back to the model, `done`
+/// means the reply to the user is complete and the turn ends.
+pub(crate) struct ToolOutput {
+    pub(crate) content: String,
+    pub(crate) done: bool,
+}
+
+/// Let the model pick tools until a tool ends the turn or it answers
+/// directly, returning the turn to remember.
+pub(crate) async fn run_turn(
+    state: &AppState,
+    history: &[ChatCompletionMessage],
+    input: &str,
+    sink: &impl AgentSink,
+) -> anyhow::Result<Turn> {
+    let mut turn = Turn::new(input);
+    let max_steps = state.config.agent.max_steps;
+    let signals = sink.signals();
+
+    for step in 1..=max_steps {
+        signals.send(SignalEvent::Step(step, max_steps).into());
+        let mut conversation = history.to_vec();
+        conversation.extend_from_slice(turn.messages());
+        let choice = chat_completion_with_tools(state, signals, conversation.clone()).await?;
+
+        match choice.finish_reason {
+            FinishReason::Stop | FinishReason::Length | FinishReason::ContentFilter => {
+                let (text, finish_reason) =
+                    stitch_reply(state, signals, conversation, choice).await?;
+                let filtered = matches!(finish_reason, FinishReason::ContentFilter);
+                let reply = sink.answer(text, filtered).await?;
+                turn.reply(reply);
+                return Ok(turn);
+            }
+            FinishReason::ToolCalls => {
+                let calls = choice.message.tool_calls;
+                turn.tool_calls(&calls);
+
+                let mut done = false;
+                for (call, output) in calls.iter().zip(sink.call_tools(&calls).await) {
+                    match output {
+                        Ok(output) => {
+                            turn.tool_result(call, output.content);
+                            done |= output.done;
+                        }
+                        // the model sees what went wrong and may try otherwise
+                        Err(e) => {
+                            warn!("tool call {} failed: {e:#}", call.function.name);
+                            turn.tool_result(call, format!("error: {e:#}"));
+                        }
+                    }
+                }
+                if done {
+                    return Ok(turn);
+                }
+            }
+            _ => bail!("stop reason not supported"),
+        }
+    }
+
+    bail!("no final answer after {max_steps} steps")
+}
+
+/// The text of a reply, continued while the model stops at the token limit,
+/// with the reason the last part ended for. `conversation` is what `choice`
+/// answered.
+async fn stitch_reply(
+    state: &AppState,
+    event_sender: &EventChannel,
+    mut conversation: Vec<ChatCompletionMessage>,
+    choice: ChatCompletionChoice,
+) -> anyhow::Result<(String, FinishReason)> {
+    let mut text = choice.message.content.unwrap_or_default();
+    let mut finish_reason = choice.finish_reason;
+    let mut part = text.clone();
+    for _ in 0..MAX_CONTINUATIONS {
+        if !matches!(finish_reason, FinishReason::Length) {
+            break;
+        }
+        info!("reply cut off after {} bytes, continue it", text.len());
+        ask_to_continue(&mut conversation, part);
+        let choice = continuation(state, event_sender, &conversation).await?;
+        part = choice.message.content.unwrap_or_default();
+        text.push_str(&part);
+        finish_reason = choice.finish_reason;
+    }
+    Ok((text, finish_reason))
+}
+
+/// Ask the model to go on from `part`, the reply it cut off.
+pub(super) fn ask_to_continue(conversation: &mut Vec<ChatCompletionMessage>, part: String) {
+    conversation.push(ChatCompletionMessage::Assistant(AssistantMessage {
+        content: Some(part),
+        name: None,
+        tool_calls: vec![],
+    }));
+    conversation.push(ChatCompletionMessage::new_user(CONTINUE_PROMPT, ""));
+}
+
+/// The next part of a cut off reply. No tools are offered, the model should
+/// only go on writing.
+async fn continuation(
+    state: &AppState,
+    event_sender: &EventChannel,
+    conversation: &[ChatCompletionMessage],
+) -> anyhow::Result<ChatCompletionChoice> {
+    let mut messages = vec![ChatCompletionMessage::new_system(
+        &state.config.prompts.tool,
+        "Ava",
+    )];
+    messages.extend_from_slice(conversation);
+    let mut res = state
+        .llm
+        .call(Operation::Chat, retrying(event_sender), |provider| {
+            let req = ChatCompletionRequest::new(provider.model, messages.clone());
+            provider.backend.chat_completion(req)
+        })
+        .await?;
+    res.choices
+        .pop()
+        .ok_or_else(|| anyhow!("expect at least one choice"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::FakeBackend;
+    use crate::{Config, Store};
+    use std::sync::Mutex;
+
+    /// Records the answers, and runs every tool call with `tool`.
+    struct TestSink {
+        signals: EventChannel,
+        answers: Mutex<Vec<(String, bool)>>,
+        tool: fn(&ToolCall) -> anyhow::Result<ToolOutput>,
+    }
+
+    #[async_trait]
+    impl AgentSink for TestSink {
+        fn signals(&self) -> &EventChannel {
+            &self.signals
+        }
+
+        async fn answer(&self, text: String, filtered: bool) -> anyhow::Result<String> {
+            self.answers.lock().unwrap().push((text.clone(), filtered));
+            Ok(text)
+        }
+
+        async fn call_tools(&self, calls: &[ToolCall]) -> Vec<anyhow::Result<ToolOutput>> {
+            calls.iter().map(self.tool).collect()
+        }
+    }
+
+    fn sink(tool: fn(&ToolCall) -> anyhow::Result<ToolOutput>) -> TestSink {
+        TestSink {
+            signals: EventChannel::new(16),
+            answers: Mutex::new(vec![]),
+            tool,
+        }
+    }
+
+    fn state(llm: &FakeBackend, max_steps: usize) -> AppState {
+        let mut config = Config::default();
+        config.agent.max_steps = max_steps;
+        AppState::new(config, Store::in_memory().unwrap(), llm.clone())
+    }
+
+    fn last_message(req: &serde_json::Value) -> &serde_json::Value {
+        req["messages"].as_array().unwrap().last().unwrap()
+    }
+
+    #[tokio::test]
+    async fn direct_answer_ends_the_turn() {
+        let llm = FakeBackend::default().reply("Hello!", "stop");
+        let sink = sink(|_| unreachable!("no tool is called"));
+        let turn = run_turn(&state(&llm, 5), &[], "Hi", &sink).await.unwrap();
+
+        assert_eq!(
+            *sink.answers.lock().unwrap(),
+            [("Hello!".to_string(), false)]
+        );
+        assert_eq!(turn.messages().len(), 2);
+        assert_eq!(llm.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reply_cut_off_at_the_limit_is_continued() {
+        let llm = FakeBackend::default()
+            .reply("Hel", "length")
+            .reply("lo", "stop");
+        let sink = sink(|_| unreachable!("no tool is called"));
+        run_turn(&state(&llm, 5), &[], "Hi", &sink).await.unwrap();
+
+        assert_eq!(
+            *sink.answers.lock().unwrap(),
+            [("Hello".to_string(), false)]
+        );
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(last_message(&requests[1])["content"], CONTINUE_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn filtered_reply_is_answered_as_filtered() {
+        let llm = FakeBackend::default().reply("Well", "content_filter");
+        let sink = sink(|_| unreachable!("no tool is called"));
+        run_turn(&state(&llm, 5), &[], "Hi", &sink).await.unwrap();
+
+        assert_eq!(*sink.answers.lock().unwrap(), [("Well".to_string(), true)]);
+    }
+
+    #[tokio::test]
+    async fn failed_tool_is_fed_back_to_the_model() {
+        let llm = FakeBackend::default()
+            .tool_call("draw_image", r#"{"prompt": "a cat"}"#)
+            .reply("Sorry, no cat today.", "stop");
+        let sink = sink(|_| Err(anyhow!("image service is down")));
+        let turn = run_turn(&state(&llm, 5), &[], "Draw a cat", &sink)
+            .await
+            .unwrap();
+
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            last_message(&requests[1])["content"],
+            "error: image service is down"
+        );
+        // input, tool call, tool result, answer
+        assert_eq!(turn.messages().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn tool_ending_the_turn_stops_the_loop() {
+        let llm = FakeBackend::default().tool_call("write_code", "{}");
+        let sink = sink(|_| {
+            Ok(ToolOutput {
+                content: "fn main() {}".to_string(),
+                done: true,
+            })
+        });
+        run_turn(&state(&llm, 5), &[], "Write code", &sink)
+            .await
+            .unwrap();
+
+        assert_eq!(llm.requests().len(), 1);
+        assert!(sink.answers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn turn_gives_up_after_max_steps() {
+        let llm = FakeBackend::default()
+            .tool_call("write_code", "{}")
+            .tool_call("write_code", "{}");
+        let sink = sink(|_| {
+            Ok(ToolOutput {
+                content: "not yet".to_string(),
+                done: false,
+            })
+        });
+        let err = run_turn(&state(&llm, 2), &[], "Write code", &sink)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.to_string(), "no final answer after 2 steps");
+        assert_eq!(llm.requests().len(), 2);
+    }
+}
@@ -1,157 +1,505 @@
-use crate::error::AppError;
+use super::agent::{ask_to_continue, run_turn, AgentSink, ToolOutput, MAX_CONTINUATIONS};
+use crate::error::{AppError, ErrorInfo, ErrorKind, Lang};
+use crate::events::EventChannel;
 use crate::handlers::{
-    AssistantEvent, AssistantStep, ChatInputEvent, ChatInputSkeletonEvent, ChatReplyEvent,
-    ChatReplySkeletonEvent, SignalEvent, SpeechResult, COOKIE_NAME,
+    current_datetime, event_sender, AssistantEvent, AssistantStep, ChatInputEvent,
+    ChatInputSkeletonEvent, ChatReplyData, ChatReplyDeltaEvent, ChatReplyEvent,
+    ChatReplySkeletonEvent, RefusalResult, SignalEvent, SpeechResult,
 };
-use crate::tools::{
-    tool_completion_request, AnswerArgs, AssistantTool, DrawImageArgs, DrawImageResult,
-    WriteCodeArgs, WriteCodeResult,
-};
-use crate::{audio_path, audio_url, image_path, image_url, EVENTS, LLM_SDK};
-use anyhow::{anyhow, bail};
-use base64::prelude::BASE64_STANDARD;
-use base64::Engine;
-use comrak::markdown_to_html_with_plugins;
-use comrak::plugins::syntect::SyntectAdapter;
+use crate::jobs::JobState;
+use crate::llm::Operation;
+use crate::memory::Turn;
+use crate::session::{owner_id, session, Session};
+use crate::store::Store;
+use crate::tools::{tool_completion_request, ToolContext};
+use crate::voice::{AudioFormat, VoiceSettings};
+use crate::{audio_path, audio_url, AppState};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use futures_util::future::join_all;
 use llm_sdk::{
-    ChatCompleteModel, ChatCompletionChoice, ChatCompletionMessage, ChatCompletionRequest,
-    CreateImageRequestBuilder, ImageResponseFormat, SpeechRequestBuilder, SpeechVoice,
-    WhisperRequestBuilder, WhisperRequestType,
+    ChatCompletionChoice, ChatCompletionMessage, ChatCompletionRequest, FinishReason, SpeechModel,
+    SpeechRequestBuilder, SpeechResponseFormat, SpeechVoice, ToolCall, WhisperRequestBuilder,
+    WhisperRequestType,
 };
-use salvo::http::form::FilePart;
-use salvo::prelude::Text;
-use salvo::{handler, Request, Response};
+use salvo::http::StatusCode;
+use salvo::prelude::{Json, Text};
+use salvo::{handler, Depot, Request, Response};
 use serde_json::json;
-use std::str::FromStr;
+use std::sync::Mutex;
 use tokio::fs;
-use tokio::sync::broadcast;
-use tracing::info;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+pub(crate) fn app_state(depot: &Depot) -> anyhow::Result<AppState> {
+    depot
+        .obtain::<AppState>()
+        .cloned()
+        .map_err(|_| anyhow!("app state not found"))
+}
+
+/// What the request tells about the client, applied to what its job sends.
+#[derive(Debug, Clone, Copy)]
+struct ClientPrefs {
+    lang: Lang,
+    /// from the `Accept` header, the page lists the formats it plays
+    audio_format: Option<AudioFormat>,
+}
+
+/// What the user sent to the assistant: a voice recording or typed text.
+enum AssistantInput {
+    Audio(Vec<u8>),
+    Text(String),
+}
+
 #[handler]
-pub async fn assistant_handler(req: &mut Request, res: &mut Response) -> Result<(), AppError> {
+pub async fn assistant_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
     info!("Request id:{:?}", req.header::<String>("x-request-id"));
     info!("enter assistant handler");
-    let device_id = req.cookie(COOKIE_NAME).unwrap().value().to_owned();
-    let event_sender = EVENTS
-        .get(&device_id)
-        .ok_or_else(|| anyhow!("device_id not found for signal sender"))?
-        .clone();
-    info!("start assist for {}", device_id);
+    let session = session(depot)?.clone();
 
     let file = req
         .file("audio")
         .await
-        .ok_or_else(|| AppError::from(anyhow!("No audio file")))?;
+        .ok_or_else(|| ErrorKind::BadInput.error("No audio file"))?;
+    // the upload is removed with the request, the job outlives it
+    let data = fs::read(file.path()).await?;
 
-    match process(&event_sender, &device_id, file).await {
-        Ok(_) => {
-            res.render(Text::Json(json!({"status": "done"}).to_string()));
-            Ok(())
+    let state = app_state(depot)?;
+    let client = ClientPrefs::from_request(req);
+    assist(state, &session, client, AssistantInput::Audio(data), res)
+}
+
+#[handler]
+pub async fn chat_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    info!("Request id:{:?}", req.header::<String>("x-request-id"));
+    info!("enter chat handler");
+    let session = session(depot)?.clone();
+
+    let text = req
+        .form::<String>("text")
+        .await
+        .filter(|v| !v.trim().is_empty())
+        .ok_or_else(|| ErrorKind::BadInput.error("No text input"))?;
+
+    let state = app_state(depot)?;
+    let client = ClientPrefs::from_request(req);
+    assist(state, &session, client, AssistantInput::Text(text), res)
+}
+
+/// Poll the state of a job, for clients without an event stream.
+#[handler]
+pub async fn job_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let owner_id = owner_id(depot)?;
+    let state = app_state(depot)?;
+    let id = req.param::<String>("id").unwrap_or_default();
+    match state.store.job(&id)? {
+        Some(job) if job.owner_id == owner_id => res.render(Json(job)),
+        _ => return Err(ErrorKind::NotFound.error("job not found").into()),
+    }
+    Ok(())
+}
+
+/// Queue the input as a job and answer with its id right away, the job
+/// reports to whoever listens on the owner's event stream.
+fn assist(
+    state: AppState,
+    session: &Session,
+    client: ClientPrefs,
+    input: AssistantInput,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let job = state.store.create_job(session.owner_id())?;
+    info!("queue job {} for {}", job.id, job.owner_id);
+
+    res.status_code(StatusCode::ACCEPTED);
+    res.render(Text::Json(
+        json!({"job_id": job.id, "status": job.state}).to_string(),
+    ));
+    let cancel = state.jobs.register(&job.id);
+    let device_id = session.device_id.clone();
+    tokio::spawn(async move {
+        let (id, owner_id) = (job.id, job.owner_id);
+        let event_sender = event_sender(&state, &owner_id);
+        let run = async {
+            event_sender.send(in_queue());
+            let _permit = state.jobs.start().await;
+            update_job(&state.store, &id, JobState::Running, None);
+            process(
+                &state,
+                &event_sender,
+                &id,
+                &owner_id,
+                &device_id,
+                client,
+                input,
+            )
+            .await
+        };
+
+        // cancelling drops `run` wherever it's waiting: on the queue, the
+        // model, or a tool, whose requests are dropped along with it
+        let ret = tokio::select! {
+            ret = run => Some(ret),
+            _ = cancel.notified() => None,
+        };
+        // a cancel that came in as the job finished still wins, the user
+        // was told it's cancelled
+        let ret = match ret {
+            Some(ret) if state.jobs.finish(&id) => Some(ret),
+            _ => None,
+        };
+        match ret {
+            Some(Ok(_)) => update_job(&state.store, &id, JobState::Done, None),
+            Some(Err(e)) => {
+                let info = ErrorInfo::new(&e, client.lang);
+                warn!("job {id} failed ({}): {e:#}", info.code);
+                update_job(&state.store, &id, JobState::Failed, Some(&info));
+                event_sender.send(error(info));
+            }
+            None => {
+                info!("job {id} cancelled");
+                event_sender.send(SignalEvent::Cancelled(id.clone()).into());
+                update_job(&state.store, &id, JobState::Cancelled, None);
+            }
         }
-        Err(e) => {
-            event_sender.send(error(e.to_string()))?;
-            res.render(Text::Json(json!({"status": "error"}).to_string()));
-            Ok(())
+    });
+    Ok(())
+}
+
+/// Stop a queued or running job of the owner.
+#[handler]
+pub async fn cancel_job_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let owner_id = owner_id(depot)?;
+    let state = app_state(depot)?;
+    let id = req.param::<String>("id").unwrap_or_default();
+    match state.store.job(&id)? {
+        Some(job) if job.owner_id == owner_id => {
+            let cancelled = state.jobs.cancel(&id);
+            res.render(Text::Json(
+                json!({"job_id": id, "cancelled": cancelled}).to_string(),
+            ));
         }
+        _ => return Err(ErrorKind::NotFound.error("job not found").into()),
     }
+    Ok(())
 }
 
+impl ClientPrefs {
+    fn from_request(req: &Request) -> Self {
+        let accept = req.header::<String>("accept").unwrap_or_default();
+        Self {
+            lang: Lang::from_request(req),
+            audio_format: AudioFormat::from_accept(&accept),
+        }
+    }
+}
+
+fn update_job(store: &Store, id: &str, state: JobState, error: Option<&ErrorInfo>) {
+    if let Err(e) = store.update_job(id, state, error) {
+        warn!("failed to update job {id} to {state}: {e}");
+    }
+}
+
+/// Run the turn `id`, its input and replies are persisted under the same id.
 async fn process(
-    event_sender: &broadcast::Sender<AssistantEvent>,
+    state: &AppState,
+    event_sender: &EventChannel,
+    id: &str,
+    owner_id: &str,
     device_id: &str,
-    data: &FilePart,
+    client: ClientPrefs,
+    input: AssistantInput,
 ) -> anyhow::Result<()> {
-    let id = Uuid::new_v4().to_string();
-    event_sender.send(in_audio_upload())?;
+    let input = match input {
+        AssistantInput::Audio(data) => {
+            event_sender.send(in_audio_upload());
 
-    info!("audio data size: {}", data.size());
+            info!("audio data size: {}", data.len());
 
-    event_sender.send(in_transcription())?;
-    event_sender.send(ChatInputSkeletonEvent::new(&id).into())?;
+            event_sender.send(in_transcription());
+            event_sender.send(ChatInputSkeletonEvent::new(id).into());
 
-    let result = fs::read(data.path()).await?;
-    let input = transcript(result).await?;
-    event_sender.send(ChatInputEvent::new(&id, &input).into())?;
+            transcript(state, event_sender, data).await?
+        }
+        AssistantInput::Text(text) => {
+            event_sender.send(ChatInputSkeletonEvent::new(id).into());
+            text
+        }
+    };
+    state
+        .store
+        .save_input(owner_id, &id, &current_datetime(), &input)?;
+    event_sender.send(ChatInputEvent::new(id, &input).into());
+
+    event_sender.send(in_thinking());
+    event_sender.send(ChatReplySkeletonEvent::new(id).into());
+
+    // replies of a signed in user show on all the user's devices, which may
+    // not play what this one accepts, so only a format picked in the
+    // settings or the configured one is used
+    let mut client = client;
+    if owner_id != device_id {
+        client.audio_format = None;
+    }
+    let history = recall(state, owner_id, id)?;
+    let sink = PageSink {
+        state,
+        event_sender,
+        chat_id: id,
+        owner_id,
+        device_id,
+        client,
+        history: &history,
+        replies: Mutex::new(ReplyNodes::new(id)),
+    };
+    let turn = run_turn(state, &history, &input, &sink).await?;
+    event_sender.send(complete());
+    state.memory.push(owner_id, turn);
+    Ok(())
+}
 
-    event_sender.send(in_thinking())?;
-    event_sender.send(ChatReplySkeletonEvent::new(&id).into())?;
+/// What Ava remembers of the owner, rebuilt from the stored chats after a
+/// restart. `chat_id` is the turn in progress, stored already.
+fn recall(
+    state: &AppState,
+    owner_id: &str,
+    chat_id: &str,
+) -> anyhow::Result<Vec<ChatCompletionMessage>> {
+    if !state.memory.contains(owner_id) {
+        // tool calls aren't stored, a turn is remembered as the input and
+        // what the replies showed
+        let turns = state
+            .store
+            .history(owner_id)?
+            .into_iter()
+            .filter(|chat| chat.id != chat_id && !chat.replies.is_empty())
+            .map(|chat| {
+                let replies: Vec<_> = chat
+                    .replies
+                    .iter()
+                    .map(|(_, reply)| reply.model_content())
+                    .collect();
+                let mut turn = Turn::new(chat.input);
+                turn.reply(replies.join("\n\n"));
+                turn
+            })
+            .collect();
+        state.memory.seed(owner_id, turns);
+    }
+    Ok(state.memory.history(owner_id))
+}
 
-    let choice = chat_completion_with_tools(&input).await?;
+/// A turn of the page: replies go into reply nodes on the owner's event
+/// stream, answers of the model are spoken.
+struct PageSink<'a> {
+    state: &'a AppState,
+    event_sender: &'a EventChannel,
+    chat_id: &'a str,
+    owner_id: &'a str,
+    device_id: &'a str,
+    client: ClientPrefs,
+    history: &'a [ChatCompletionMessage],
+    replies: Mutex<ReplyNodes>,
+}
 
-    match choice.finish_reason {
-        llm_sdk::FinishReason::Stop => {
-            let output = choice
-                .message
-                .content
-                .ok_or_else(|| anyhow!("expect content but no content available"))?;
-            event_sender.send(in_speech())?;
-            let ret = SpeechResult::new_text_only(&output);
-            event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
+#[async_trait]
+impl AgentSink for PageSink<'_> {
+    fn signals(&self) -> &EventChannel {
+        self.event_sender
+    }
 
-            let ret = speech(device_id, &output).await?;
-            event_sender.send(complete())?;
-            event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
+    async fn answer(&self, text: String, filtered: bool) -> anyhow::Result<String> {
+        let (state, event_sender, id) = (self.state, self.event_sender, self.chat_id);
+        if text.is_empty() && !filtered {
+            bail!("expect content but no content available");
+        }
+        // what made it through is still spoken
+        if !text.is_empty() {
+            let reply_id = self.next_reply();
+            // loaded only now, a tool may just have changed them
+            let settings =
+                VoiceSettings::of(state, Some(self.device_id)).accepting(self.client.audio_format);
+            let ret = speak(
+                state,
+                event_sender,
+                self.owner_id,
+                &settings,
+                &reply_id,
+                &text,
+            )
+            .await?;
+            event_sender.send(final_reply(&state.store, id, &reply_id, ret)?);
         }
-        llm_sdk::FinishReason::ToolCalls => {
-            let tool_call = &choice.message.tool_calls[0].function;
-            match AssistantTool::from_str(&tool_call.name) {
-                Ok(AssistantTool::DrawImage) => {
-                    let args: DrawImageArgs = serde_json::from_str(&tool_call.arguments)?;
-
-                    event_sender.send(in_draw_image())?;
-                    let ret = DrawImageResult::new("", &args.prompt);
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-
-                    let ret = draw_image(device_id, args).await?;
-                    event_sender.send(complete())?;
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-                }
-                Ok(AssistantTool::WriteCode) => {
-                    event_sender.send(in_write_code())?;
-                    let ret = write_code(serde_json::from_str(&tool_call.arguments)?).await?;
-                    event_sender.send(complete())?;
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-                }
-
-                Ok(AssistantTool::Answer) => {
-                    event_sender.send(in_chat_completion())?;
-                    let output = answer(serde_json::from_str(&tool_call.arguments)?).await?;
-                    event_sender.send(complete())?;
-                    let ret = SpeechResult::new_text_only(&output);
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-
-                    event_sender.send(in_speech())?;
-                    let ret = speech(device_id, &output).await?;
-                    event_sender.send(complete())?;
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-                }
-                _ => {
-                    bail!("no proper tool found at the moment")
-                }
+        let mut reply = text;
+        if filtered {
+            info!("reply of {id} blocked by the content filter");
+            let refusal = RefusalResult::new(self.client.lang);
+            if !reply.is_empty() {
+                reply.push_str("\n\n");
             }
+            reply.push_str(refusal.message());
+            let reply_id = self.next_reply();
+            event_sender.send(final_reply(&state.store, id, &reply_id, refusal)?);
         }
-        _ => {
-            bail!("stop reason not supported")
+        Ok(reply)
+    }
+
+    /// Tools run concurrently, each into a reply node of its own.
+    async fn call_tools(&self, calls: &[ToolCall]) -> Vec<anyhow::Result<ToolOutput>> {
+        let mut tasks = Vec::with_capacity(calls.len());
+        for call in calls {
+            let reply_id = self.next_reply();
+            tasks.push(async move {
+                let ctx = ToolContext {
+                    state: self.state,
+                    event_sender: self.event_sender,
+                    owner_id: self.owner_id,
+                    device_id: Some(self.device_id),
+                    audio_format: self.client.audio_format,
+                    lang: self.client.lang,
+                    reply_id: &reply_id,
+                    history: self.history,
+                };
+                call_tool(&ctx, self.chat_id, call).await
+            });
         }
+        join_all(tasks).await
     }
+}
 
-    Ok(())
+impl PageSink<'_> {
+    fn next_reply(&self) -> String {
+        self.replies.lock().unwrap().next(self.event_sender)
+    }
 }
 
-async fn transcript(data: Vec<u8>) -> anyhow::Result<String> {
+async fn call_tool(
+    ctx: &ToolContext<'_>,
+    chat_id: &str,
+    call: &ToolCall,
+) -> anyhow::Result<ToolOutput> {
+    let function = &call.function;
+    let tool = ctx
+        .state
+        .tools
+        .get(&function.name)
+        .ok_or_else(|| anyhow!("no proper tool found for {}", function.name))?;
+
+    ctx.event_sender
+        .send(SignalEvent::Tool(tool.progress().to_string()).into());
+    let ret = tool.call(ctx, &function.arguments).await?;
+    let content = ret.model_content();
+    ctx.event_sender
+        .send(final_reply(&ctx.state.store, chat_id, ctx.reply_id, ret)?);
+    Ok(ToolOutput {
+        content,
+        done: tool.ends_turn(),
+    })
+}
+
+/// Show `text` in the reply node right away, then turn it into speech.
+pub(crate) async fn speak(
+    state: &AppState,
+    event_sender: &EventChannel,
+    owner_id: &str,
+    settings: &VoiceSettings,
+    reply_id: &str,
+    text: &str,
+) -> anyhow::Result<SpeechResult> {
+    event_sender.send(in_speech());
+    let ret = SpeechResult::new_text_only(text);
+    event_sender.send(ChatReplyEvent::new(reply_id, ret).into());
+
+    speech(state, event_sender, owner_id, settings, text).await
+}
+
+/// Hands out reply nodes of a turn: the first reply goes into the skeleton
+/// created with the turn, every later one gets a skeleton of its own.
+struct ReplyNodes {
+    chat_id: String,
+    count: usize,
+}
+
+impl ReplyNodes {
+    fn new(chat_id: impl Into<String>) -> Self {
+        Self {
+            chat_id: chat_id.into(),
+            count: 0,
+        }
+    }
+
+    fn next(&mut self, event_sender: &EventChannel) -> String {
+        self.count += 1;
+        if self.count == 1 {
+            return self.chat_id.clone();
+        }
+        let id = format!("{}-{}", self.chat_id, self.count);
+        event_sender.send(ChatReplySkeletonEvent::new(&id).into());
+        id
+    }
+}
+
+/// Tell the user a failed call to the AI provider is retried.
+pub(crate) fn retrying(event_sender: &EventChannel) -> impl Fn(usize, usize) + '_ {
+    move |attempt, max| event_sender.send(SignalEvent::Retrying(attempt, max).into())
+}
+
+pub(crate) async fn transcript(
+    state: &AppState,
+    event_sender: &EventChannel,
+    data: Vec<u8>,
+) -> anyhow::Result<String> {
     let req = WhisperRequestBuilder::default()
         .file(data)
-        .prompt("If audio language is Chinese, please use Simplified Chinese")
+        .prompt(&state.config.whisper.prompt)
         .request_type(WhisperRequestType::Transcription)
         .build()?;
-    let res = LLM_SDK.whisper(req).await?;
+    let res = state
+        .llm
+        .call(
+            Operation::Transcription,
+            retrying(event_sender),
+            |provider| provider.backend.whisper(req.clone()),
+        )
+        .await?;
     Ok(res.text)
 }
 
-async fn chat_completion_with_tools(prompt: &str) -> anyhow::Result<ChatCompletionChoice> {
-    let req = tool_completion_request(prompt, "");
-    let mut res = LLM_SDK.chat_completion(req).await?;
+pub(crate) async fn chat_completion_with_tools(
+    state: &AppState,
+    event_sender: &EventChannel,
+    conversation: Vec<ChatCompletionMessage>,
+) -> anyhow::Result<ChatCompletionChoice> {
+    let mut res = state
+        .llm
+        .call(Operation::Chat, retrying(event_sender), |provider| {
+            let req = tool_completion_request(
+                &state.config,
+                &state.tools,
+                provider.model,
+                conversation.clone(),
+            );
+            provider.backend.chat_completion(req)
+        })
+        .await?;
     let choice = res
         .choices
         .pop()
@@ -159,87 +507,144 @@ async fn chat_completion_with_tools(prompt: &str) -> anyhow::Result<ChatCompleti
     Ok(choice)
 }
 
-async fn chat_completion(messages: Vec<ChatCompletionMessage>) -> anyhow::Result<String> {
-    let req = ChatCompletionRequest::new(ChatCompleteModel::default(), messages);
-    let mut res = LLM_SDK.chat_completion(req).await?;
-    let content = res
-        .choices
-        .pop()
-        .ok_or_else(|| anyhow!("expect at least one choice"))?
-        .message
-        .content
-        .ok_or_else(|| anyhow!("expect content but no content available"))?;
-    Ok(content)
+/// The text of a streamed completion, `filtered` if the content filter cut
+/// it short.
+pub(crate) struct Completion {
+    pub(crate) text: String,
+    pub(crate) filtered: bool,
 }
 
-async fn speech(device_id: &str, text: &str) -> anyhow::Result<SpeechResult> {
-    let req = SpeechRequestBuilder::default()
-        .input(text)
-        // switch voice
-        .voice(SpeechVoice::Alloy)
-        .build()?;
-    let data = LLM_SDK.speech(req).await?;
-    let uuid = Uuid::new_v4().to_string();
-    let path = audio_path(device_id, &uuid);
-    if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            tokio::fs::create_dir_all(parent).await?;
+impl Completion {
+    /// The text, telling the user the rest was blocked if it was.
+    pub(crate) fn with_refusal(self, lang: Lang) -> String {
+        if !self.filtered {
+            return self.text;
         }
+        let refusal = RefusalResult::new(lang);
+        format!("{}\n\n{}", self.text, refusal.message())
     }
-    tokio::fs::write(&path, data).await?;
-    Ok(SpeechResult::new(text, audio_url(device_id, &uuid)))
 }
 
-async fn draw_image(device_id: &str, args: DrawImageArgs) -> anyhow::Result<DrawImageResult> {
-    let req = CreateImageRequestBuilder::default()
-        .prompt(args.prompt)
-        .response_format(ImageResponseFormat::B64Json)
+/// Stream the completion to the reply node of `id`, returning the full text
+/// once done. A completion cut off at the token limit is continued.
+pub(crate) async fn chat_completion(
+    state: &AppState,
+    event_sender: &EventChannel,
+    id: &str,
+    mut messages: Vec<ChatCompletionMessage>,
+) -> anyhow::Result<Completion> {
+    let mut text = String::new();
+    let mut filtered = false;
+    for n in 0..=MAX_CONTINUATIONS {
+        let (part, finish_reason) = stream_completion(state, event_sender, id, &messages).await?;
+        text.push_str(&part);
+        match finish_reason {
+            Some(FinishReason::Length) if n < MAX_CONTINUATIONS => {
+                info!("reply {id} cut off after {} bytes, continue it", text.len());
+                ask_to_continue(&mut messages, part);
+            }
+            Some(FinishReason::ContentFilter) => {
+                info!("reply {id} blocked by the content filter");
+                filtered = true;
+                break;
+            }
+            _ => break,
+        }
+    }
+    if text.is_empty() && !filtered {
+        bail!("expect content but no content available");
+    }
+    Ok(Completion { text, filtered })
+}
+
+/// Stream one completion to the reply node of `id`, returning its text and
+/// why it ended.
+async fn stream_completion(
+    state: &AppState,
+    event_sender: &EventChannel,
+    id: &str,
+    messages: &[ChatCompletionMessage],
+) -> anyhow::Result<(String, Option<FinishReason>)> {
+    // only getting the stream is retried, deltas may already be shown after
+    let mut stream = state
+        .llm
+        .call(Operation::Chat, retrying(event_sender), |provider| {
+            let req = ChatCompletionRequest::new(provider.model, messages.to_vec());
+            provider.backend.chat_completion_stream(req)
+        })
+        .await?;
+    let timeout = state.llm.timeout(Operation::Chat);
+    let mut content = String::new();
+    let mut finish_reason = None;
+    loop {
+        let next = tokio::time::timeout(timeout, stream.next())
+            .await
+            .with_context(|| format!("chat stalled for {}s", timeout.as_secs()))?;
+        let Some(delta) = next else {
+            break;
+        };
+        let delta = delta?;
+        if !delta.content.is_empty() {
+            event_sender.send(ChatReplyDeltaEvent::new(id, &delta.content).into());
+            content.push_str(&delta.content);
+        }
+        if delta.finish_reason.is_some() {
+            finish_reason = delta.finish_reason;
+        }
+    }
+    Ok((content, finish_reason))
+}
+
+pub(crate) async fn speech(
+    state: &AppState,
+    event_sender: &EventChannel,
+    owner_id: &str,
+    settings: &VoiceSettings,
+    text: &str,
+) -> anyhow::Result<SpeechResult> {
+    let format = settings.format.unwrap_or(state.config.speech.format);
+    let req = SpeechRequestBuilder::default()
+        .input(text)
+        .voice(SpeechVoice::from(settings.voice))
+        .speed(settings.speed)
+        .model(SpeechModel::from(settings.model))
+        .response_format(SpeechResponseFormat::from(format))
         .build()?;
-    let mut ret = LLM_SDK.create_image(req).await?;
-    let img = ret
-        .data
-        .pop()
-        .ok_or_else(|| anyhow!("expect at least one data"))?;
-    let data = BASE64_STANDARD.decode(img.b64_json.unwrap())?;
+    let data = state
+        .llm
+        .call(Operation::Speech, retrying(event_sender), |provider| {
+            provider.backend.speech(req.clone())
+        })
+        .await?;
     let uuid = Uuid::new_v4().to_string();
-    let path = image_path(device_id, &uuid);
+    let path = audio_path(owner_id, &uuid, format);
     if let Some(parent) = path.parent() {
         if !parent.exists() {
-            fs::create_dir_all(parent).await?;
+            tokio::fs::create_dir_all(parent).await?;
         }
     }
-    fs::write(&path, data).await?;
-    Ok(DrawImageResult::new(
-        image_url(device_id, &uuid),
-        img.revised_prompt,
+    tokio::fs::write(&path, data).await?;
+    Ok(SpeechResult::new(
+        text,
+        audio_url(&state.assets, owner_id, &uuid, format),
+        format,
     ))
 }
 
-async fn write_code(args: WriteCodeArgs) -> anyhow::Result<WriteCodeResult> {
-    let messages = vec![
-        ChatCompletionMessage::new_system("I'm an expert on coding, I'll write code for you in markdown format based on your prompt", "Ava"),
-        ChatCompletionMessage::new_user(args.prompt, ""),
-
-    ];
-    let md = chat_completion(messages).await?;
-    Ok(WriteCodeResult::new(md2html(&md)))
-}
-
-async fn answer(args: AnswerArgs) -> anyhow::Result<String> {
-    let messages = vec![
-        ChatCompletionMessage::new_system("I can help answer anything you'd like to chat", "Ava"),
-        ChatCompletionMessage::new_user(args.prompt, ""),
-    ];
-    chat_completion(messages).await
+/// The final content of a reply node, persisted so it shows up again after reload.
+fn final_reply(
+    store: &Store,
+    chat_id: &str,
+    id: &str,
+    data: impl Into<ChatReplyData>,
+) -> anyhow::Result<AssistantEvent> {
+    let data = data.into();
+    store.save_reply(chat_id, id, &data)?;
+    Ok(ChatReplyEvent::new(id, data).into())
 }
 
-fn md2html(md: &str) -> String {
-    let adapter = SyntectAdapter::new(Some("Solarized (dark)"));
-    let options = comrak::Options::default();
-    let mut plugins = comrak::Plugins::default();
-
-    plugins.render.codefence_syntax_highlighter = Some(&adapter);
-    markdown_to_html_with_plugins(md, &options, &plugins)
+fn in_queue() -> AssistantEvent {
+    SignalEvent::Processing(AssistantStep::Queued).into()
 }
 
 fn in_audio_upload() -> AssistantEvent {
@@ -254,26 +659,78 @@ fn in_thinking() -> AssistantEvent {
     SignalEvent::Processing(AssistantStep::Thinking).into()
 }
 
-fn in_chat_completion() -> AssistantEvent {
-    SignalEvent::Processing(AssistantStep::ChatCompletion).into()
-}
-
 fn in_speech() -> AssistantEvent {
     SignalEvent::Processing(AssistantStep::Speech).into()
 }
 
-fn in_draw_image() -> AssistantEvent {
-    SignalEvent::Processing(AssistantStep::DrawImage).into()
+fn complete() -> AssistantEvent {
+    SignalEvent::Complete.into()
 }
 
-fn in_write_code() -> AssistantEvent {
-    SignalEvent::Processing(AssistantStep::WriteCode).into()
+fn error(info: ErrorInfo) -> AssistantEvent {
+    SignalEvent::Error(info).into()
 }
 
-fn complete() -> AssistantEvent {
-    SignalEvent::Complete.into()
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::llm::FakeBackend;
+    use crate::{Config, Store};
+
+    fn state(llm: &FakeBackend) -> AppState {
+        AppState::new(Config::default(), Store::in_memory().unwrap(), llm.clone())
+    }
+
+    fn user(prompt: &str) -> Vec<ChatCompletionMessage> {
+        vec![ChatCompletionMessage::new_user(prompt, "")]
+    }
+
+    #[tokio::test]
+    async fn streamed_reply_cut_off_is_continued() {
+        let llm = FakeBackend::default()
+            .reply("fn main() {", "length")
+            .reply("}", "stop");
+        let events = EventChannel::new(16);
+        let completion = chat_completion(&state(&llm), &events, "1", user("code"))
+            .await
+            .unwrap();
+
+        assert_eq!(completion.text, "fn main() {}");
+        assert!(!completion.filtered);
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 2);
+        let messages = requests[1]["messages"].as_array().unwrap();
+        assert_eq!(messages[1]["content"], "fn main() {");
+    }
 
-fn error(msg: impl Into<String>) -> AssistantEvent {
-    SignalEvent::Error(msg.into()).into()
+    #[tokio::test]
+    async fn streamed_reply_blocked_tells_the_refusal() {
+        let llm = FakeBackend::default().reply("Once upon", "content_filter");
+        let events = EventChannel::new(16);
+        let completion = chat_completion(&state(&llm), &events, "1", user("story"))
+            .await
+            .unwrap();
+
+        assert!(completion.filtered);
+        assert_eq!(
+            completion.with_refusal(Lang::En),
+            format!(
+                "Once upon\n\n{}",
+                ErrorKind::ContentFilter.message(Lang::En)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn streamed_reply_blocked_entirely_is_empty() {
+        let llm = FakeBackend::default().reply("", "content_filter");
+        let events = EventChannel::new(16);
+        let completion = chat_completion(&state(&llm), &events, "1", user("story"))
+            .await
+            .unwrap();
+
+        assert!(completion.filtered);
+        assert!(completion.text.is_empty());
+    }
 }
@@ -0,0 +1,195 @@
+use super::app_state;
+use crate::error::{AppError, ErrorKind};
+use crate::oidc::LoginState;
+use crate::session::{device_label, session};
+use crate::AppState;
+use anyhow::{anyhow, bail};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use salvo::http::cookie::{Cookie, SameSite};
+use salvo::prelude::Redirect;
+use salvo::{handler, Depot, Request, Response};
+use time::Duration;
+use tracing::{info, warn};
+
+const OIDC_COOKIE_NAME: &str = "oidc_login";
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Create a user with a password and sign the device in as it.
+#[handler]
+pub async fn register_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let (username, password) = credentials(req).await?;
+    if password.len() < MIN_PASSWORD_LEN {
+        return Err(ErrorKind::BadInput
+            .error(format!(
+                "password must be at least {MIN_PASSWORD_LEN} characters"
+            ))
+            .into());
+    }
+
+    let hash = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow!("failed to hash password: {e}"))
+    })
+    .await??;
+
+    let state = app_state(depot)?;
+    let session = session(depot)?;
+    let user = state
+        .store
+        .create_user(Some(&username), &username, Some(&hash))?;
+    link_device(&state, &session.device_id, &user.id, &device_label(req))?;
+    info!("user {} registered", user.id);
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
+
+/// Sign the device in with username and password.
+#[handler]
+pub async fn login_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let (username, password) = credentials(req).await?;
+    let user = state.store.password_user(&username)?;
+    let verified = match user {
+        Some((user, hash)) => {
+            let valid = tokio::task::spawn_blocking(move || {
+                PasswordHash::new(&hash).is_ok_and(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+            })
+            .await?;
+            valid.then_some(user)
+        }
+        None => None,
+    };
+
+    let Some(user) = verified else {
+        warn!("failed login of {username}");
+        return Err(ErrorKind::Unauthorized
+            .error("invalid username or password")
+            .into());
+    };
+    let session = session(depot)?;
+    link_device(&state, &session.device_id, &user.id, &device_label(req))?;
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
+
+/// Sign the device out, it's anonymous again afterwards.
+#[handler]
+pub async fn logout_handler(depot: &mut Depot, res: &mut Response) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let session = session(depot)?;
+    state.store.unlink_device(&session.device_id)?;
+    if let Some(user) = &session.user {
+        state.events.revoke(&user.id, &session.device_id);
+    }
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
+
+/// Send the browser to the OIDC provider.
+#[handler]
+pub async fn oidc_login_handler(depot: &mut Depot, res: &mut Response) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let oidc = state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| anyhow!("oidc is not configured"))?;
+    let (url, login) = oidc.authorize_url().await?;
+
+    res.add_cookie(oidc_cookie(login.to_cookie_value(), Duration::minutes(10)));
+    res.render(Redirect::found(url.as_str()));
+    Ok(())
+}
+
+/// The provider sends the browser back here, sign the device in as the user
+/// of the provider's identity, creating the user on first sign in.
+#[handler]
+pub async fn oidc_callback_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let oidc = state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| anyhow!("oidc is not configured"))?;
+
+    let login = req
+        .cookie(OIDC_COOKIE_NAME)
+        .and_then(|cookie| LoginState::from_cookie_value(cookie.value()))
+        .ok_or_else(|| ErrorKind::BadInput.error("sign in expired, please try again"))?;
+    res.add_cookie(oidc_cookie(String::new(), Duration::ZERO));
+    if req.query::<String>("state").as_deref() != Some(login.state.as_str()) {
+        return Err(ErrorKind::BadInput.error("sign in state mismatch").into());
+    }
+    let code = match req.query::<String>("code") {
+        Some(code) => code,
+        None => {
+            let error = req.query::<String>("error").unwrap_or_default();
+            return Err(ErrorKind::BadInput
+                .error(format!("sign in failed: {error}"))
+                .into());
+        }
+    };
+
+    let info = oidc.user_info(&code, &login.verifier).await?;
+    let user = state
+        .store
+        .identity_user(oidc.issuer(), &info.sub, info.display_name())?;
+    let session = session(depot)?;
+    link_device(&state, &session.device_id, &user.id, &device_label(req))?;
+    info!("user {} signed in via {}", user.id, oidc.issuer());
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
+
+/// Sign the device in as the user, its chats move to the user along with
+/// what Ava remembers of them.
+pub(crate) fn link_device(
+    state: &AppState,
+    device_id: &str,
+    user_id: &str,
+    label: &str,
+) -> anyhow::Result<()> {
+    state.store.link_device(device_id, user_id, label)?;
+    state.memory.rename(device_id, user_id);
+    Ok(())
+}
+
+/// Carries the `LoginState` from the login request to the callback.
+fn oidc_cookie(value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build((OIDC_COOKIE_NAME, value))
+        .path("/auth/oidc")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .build()
+}
+
+async fn credentials(req: &mut Request) -> anyhow::Result<(String, String)> {
+    let username = req.form::<String>("username").await.unwrap_or_default();
+    let password = req.form::<String>("password").await.unwrap_or_default();
+    let username = username.trim().to_string();
+    if username.is_empty() || password.is_empty() {
+        bail!(ErrorKind::BadInput.error("username and password are required"));
+    }
+    Ok((username, password))
+}
@@ -1,49 +1,90 @@
-use crate::handlers::{AssistantEvent, COOKIE_NAME};
-use crate::EVENTS;
-use dashmap::DashMap;
-use salvo::prelude::SseKeepAlive;
+use crate::error::AppError;
+use crate::events::EventChannel;
+use crate::handlers::app_state;
+use crate::handlers::AssistantEvent;
+use crate::session::session;
+use crate::AppState;
+use salvo::prelude::{Json, SseKeepAlive};
 use salvo::sse::SseEvent;
-use salvo::{handler, Request, Response};
+use salvo::{handler, Depot, Request, Response};
+use serde_json::json;
 use std::convert::Infallible;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::broadcast;
 use tokio_stream::{wrappers::BroadcastStream, StreamExt as _};
 use tracing::info;
 
-const MAX_EVENTS: usize = 128;
 #[handler]
-pub async fn events_handler(req: &mut Request, res: &mut Response) {
-    let device_id = req.cookie(COOKIE_NAME).unwrap().value().to_owned();
+pub async fn events_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let session = session(depot)?;
+    let owner_id = session.owner_id().to_string();
+    let device_id = session.device_id.clone();
+    let last_event_id = req.header::<String>("last-event-id");
+
+    info!("user {owner_id} connected (last event: {last_event_id:?})");
+    sse_handler(&state, &owner_id, &device_id, last_event_id.as_deref(), res).await;
+    Ok(())
+}
 
-    info!("user {device_id} connected");
-    sse_handler(&device_id, &EVENTS, res).await;
+/// The channel of the owner's events.
+pub(crate) fn event_sender(state: &AppState, owner_id: &str) -> Arc<EventChannel> {
+    state.events.channel(owner_id)
+}
+
+/// Counts of connected devices for monitoring.
+#[handler]
+pub async fn status_handler(depot: &mut Depot, res: &mut Response) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    res.render(Json(json!({ "events": state.events.stats() })));
+    Ok(())
 }
 
 async fn sse_handler(
+    state: &AppState,
+    owner_id: &str,
     device_id: &str,
-    map: &DashMap<String, broadcast::Sender<AssistantEvent>>,
+    last_event_id: Option<&str>,
     res: &mut Response,
 ) {
-    let rx = if let Some(tx) = map.get(device_id) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(MAX_EVENTS);
-        map.insert(device_id.to_string(), tx);
-        rx
-    };
+    let channel = event_sender(state, owner_id);
+    // a device signed out or revoked elsewhere must not see the user's events
+    // anymore, the browser reconnects as the device itself
+    let revoked = channel.revoked(device_id);
+    let (missed, rx) = channel.subscribe(last_event_id);
+    if !missed.is_empty() {
+        info!("replay {} events to {owner_id}", missed.len());
+    }
 
-    let stream = BroadcastStream::new(rx)
-        .filter_map(|v| v.ok())
+    // a lagging receiver ends the stream, the browser reconnects with the
+    // last id it got and the rest is replayed
+    let live = BroadcastStream::new(rx).map_while(|v| v.ok());
+    let missed = missed.into_iter().map(|mut v| {
+        v.event = v.event.replayed();
+        v
+    });
+    let events = tokio_stream::iter(missed).chain(live);
+    let stream = futures_util::StreamExt::take_until(events, revoked)
         .map(|v| {
-            let (event, id) = match &v {
-                AssistantEvent::Signal(_) => ("signal", "".to_string()),
-                AssistantEvent::InputSkeleton(_) => ("input_skeleton", "".to_string()),
-                AssistantEvent::Input(v) => ("input", v.id.clone()),
-                AssistantEvent::ReplySkeleton(_) => ("reply_skeleton", "".to_string()),
-                AssistantEvent::Reply(v) => ("reply", v.id.clone()),
+            // node ids go first in the data, the event id is the sequence id
+            let (event, target) = match &v.event {
+                AssistantEvent::Signal(_) => ("signal", None),
+                AssistantEvent::InputSkeleton(_) => ("input_skeleton", None),
+                AssistantEvent::Input(v) => ("input", Some(v.id.clone())),
+                AssistantEvent::ReplySkeleton(_) => ("reply_skeleton", None),
+                AssistantEvent::Reply(v) => ("reply", Some(v.id.clone())),
+                AssistantEvent::ReplyDelta(v) => ("reply_delta", Some(v.id.clone())),
+            };
+            let content: String = v.event.into();
+            let data = match target {
+                Some(target) => format!("{target}\n{content}"),
+                None => content,
             };
-            let data: String = v.into();
-            SseEvent::default().name(event).text(data).id(id)
+            SseEvent::default().name(event).text(data).id(v.id)
         })
         .map(Ok::<_, Infallible>);
     SseKeepAlive::new(stream)
@@ -1,27 +1,75 @@
+use crate::error::AppError;
+use crate::handlers::{
+    app_state, ChatInputEvent, ChatInputSkeletonEvent, ChatReplyEvent, ChatReplySkeletonEvent,
+};
+use crate::session::session;
+use crate::store::{ChatRecord, Device};
+use crate::voice::{AudioFormat, Voice, VoiceModel, VoiceSettings};
 use askama::Template;
-use salvo::http::cookie::Cookie;
 use salvo::prelude::Text;
-use salvo::{handler, Request, Response};
-use uuid::Uuid;
-
-pub const COOKIE_NAME: &str = "device_id";
+use salvo::{handler, Depot, Response};
+use strum::IntoEnumIterator;
+use tracing::warn;
 
 #[derive(Debug, Template)]
 #[template(path = "index.html.j2")]
-struct IndexTemplate {}
+struct IndexTemplate {
+    // rendered input and reply nodes of previous turns
+    chats: Vec<String>,
+    // name of the signed in user
+    user: Option<String>,
+    // label of the OIDC sign in button, if configured
+    sso: Option<String>,
+    device_id: String,
+    // devices signed in as the user
+    devices: Vec<Device>,
+    // voice settings of this device, and the choices for them
+    voice: VoiceSettings,
+    voices: Vec<Voice>,
+    models: Vec<VoiceModel>,
+    formats: Vec<AudioFormat>,
+}
 
 #[handler]
-pub async fn index_page(req: &mut Request, res: &mut Response) {
-    let device_id_cookie = req.cookies().get(COOKIE_NAME).cloned().unwrap_or_else(|| {
-        let new_id = Uuid::new_v4().to_string();
-        Cookie::build((COOKIE_NAME, new_id))
-            .path("/")
-            .secure(true)
-            .permanent()
-            .build()
-    });
+pub async fn index_page(depot: &mut Depot, res: &mut Response) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let session = session(depot)?;
+
+    let chats = match state.store.history(session.owner_id()) {
+        Ok(records) => records.into_iter().flat_map(render_chat).collect(),
+        Err(e) => {
+            warn!("failed to load chat history: {e}");
+            vec![]
+        }
+    };
+
+    let devices = match &session.user {
+        Some(user) => state.store.user_devices(&user.id)?,
+        None => vec![],
+    };
+
+    let index_template = IndexTemplate {
+        chats,
+        user: session.user.as_ref().map(|user| user.name.clone()),
+        sso: state.oidc.as_ref().map(|oidc| oidc.label().to_string()),
+        device_id: session.device_id.clone(),
+        devices,
+        voice: VoiceSettings::of(&state, Some(&session.device_id)),
+        voices: Voice::iter().collect(),
+        models: VoiceModel::iter().collect(),
+        formats: AudioFormat::iter().collect(),
+    };
+    res.render(Text::Html(index_template.render()?));
+    Ok(())
+}
 
-    let index_template = IndexTemplate {};
-    res.add_cookie(device_id_cookie)
-        .render(Text::Html(index_template.render().unwrap()));
+fn render_chat(record: ChatRecord) -> Vec<String> {
+    let input = ChatInputEvent::new(&record.id, record.input);
+    let mut nodes =
+        vec![ChatInputSkeletonEvent::new_with_content(&record.id, record.datetime, input).into()];
+    for (id, data) in record.replies {
+        let reply = ChatReplyEvent::new(&id, data.without_autoplay());
+        nodes.push(ChatReplySkeletonEvent::new_with_content(&id, reply).into());
+    }
+    nodes
 }
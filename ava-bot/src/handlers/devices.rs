@@ -0,0 +1,110 @@
+use super::{app_state, link_device};
+use crate::error::{AppError, ErrorKind};
+use crate::pairing::{display_code, qr_svg, PAIRING_TTL};
+use crate::session::{device_label, session};
+use crate::AppState;
+use askama::Template;
+use salvo::prelude::{Redirect, Text};
+use salvo::{handler, Depot, Request, Response};
+use tracing::info;
+
+/// Name of the user an anonymous device becomes when it pairs another one.
+const GUEST_NAME: &str = "Guest";
+
+#[derive(Debug, Template)]
+#[template(path = "pair.html.j2")]
+struct PairTemplate {
+    code: String,
+    // QR code of the pairing url, shown to the device creating the code
+    qr: Option<String>,
+    ttl_minutes: u64,
+}
+
+/// Create a pairing code for the user of the device and show it with a QR
+/// code of the url redeeming it.
+#[handler]
+pub async fn pairing_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let session = session(depot)?;
+    let user_id = match &session.user {
+        Some(user) => user.id.clone(),
+        None => {
+            // the chats of the device move to the new user, so both devices share them
+            let user = state.store.create_user(None, GUEST_NAME, None)?;
+            link_device(&state, &session.device_id, &user.id, &device_label(req))?;
+            user.id
+        }
+    };
+
+    let code = state.pairings.create(&user_id);
+    // not the Host header, it's up to the client and ends up in the QR code
+    let url = format!("{}/pair/{code}", state.config.public_url());
+    let template = PairTemplate {
+        code: display_code(&code),
+        qr: Some(qr_svg(&url)?),
+        ttl_minutes: PAIRING_TTL.as_secs() / 60,
+    };
+    res.render(Text::Html(template.render()?));
+    Ok(())
+}
+
+/// The url in the QR code, asks before joining so a link alone can't move a
+/// device into someone else's account.
+#[handler]
+pub async fn pair_page(req: &mut Request, res: &mut Response) -> Result<(), AppError> {
+    let code = req.param::<String>("code").unwrap_or_default();
+    let template = PairTemplate {
+        code,
+        qr: None,
+        ttl_minutes: PAIRING_TTL.as_secs() / 60,
+    };
+    res.render(Text::Html(template.render()?));
+    Ok(())
+}
+
+/// Redeem a pairing code, signing the device in as the user who created it.
+#[handler]
+pub async fn join_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let code = req.form::<String>("code").await.unwrap_or_default();
+    let user_id = state
+        .pairings
+        .redeem(&code)
+        .ok_or_else(|| ErrorKind::BadInput.error("invalid or expired pairing code"))?;
+
+    let session = session(depot)?;
+    link_device(&state, &session.device_id, &user_id, &device_label(req))?;
+    info!("device {} paired to user {user_id}", session.device_id);
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
+
+/// Sign a device of the user out, the device itself included.
+#[handler]
+pub async fn revoke_device_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let device_id = req.param::<String>("device_id").unwrap_or_default();
+    let session = session(depot)?;
+    let user = match (&session.user, state.store.device_user(&device_id)?) {
+        (Some(user), Some(owner)) if user.id == owner.id => user,
+        _ => return Err(ErrorKind::NotFound.error("device not found").into()),
+    };
+
+    state.store.unlink_device(&device_id)?;
+    state.events.revoke(&user.id, &device_id);
+    info!("device {device_id} revoked by {}", session.device_id);
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
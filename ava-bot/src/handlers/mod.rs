@@ -1,17 +1,28 @@
+mod agent;
 mod assistant;
+mod auth;
 mod chats;
 mod common;
+mod devices;
+mod openai;
+mod settings;
 
 use askama::Template;
 pub use assistant::*;
+pub use auth::*;
 pub use chats::*;
 pub use common::*;
 use derive_more::From;
+pub use devices::*;
+pub use openai::{chat_completions_handler, models_handler};
+pub use settings::*;
 use std::fmt::Debug;
 
-use crate::tools::{DrawImageResult, WriteCodeResult};
+use crate::error::{ErrorInfo, ErrorKind, Lang};
+use crate::tools::{DrawImageResult, VoiceSettingsResult, WriteCodeResult};
+use crate::voice::AudioFormat;
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumString};
+use strum::{AsRefStr, Display, EnumString};
 use time::macros::{format_description, offset};
 use time::OffsetDateTime;
 
@@ -22,6 +33,7 @@ pub(crate) enum AssistantEvent {
     Input(ChatInputEvent),
     ReplySkeleton(ChatReplySkeletonEvent),
     Reply(ChatReplyEvent),
+    ReplyDelta(ChatReplyDeltaEvent),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -29,7 +41,16 @@ pub(crate) enum AssistantEvent {
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub(crate) enum SignalEvent {
     Processing(AssistantStep),
-    Error(String),
+    /// (current, max) round of querying the model in the tool loop
+    Step(usize, usize),
+    /// progress of a running tool, e.g. "Drawing image"
+    Tool(String),
+    /// (attempt, max) of a failed call to the AI provider being retried
+    Retrying(usize, usize),
+    /// what stopped the turn, shown to the user in their language
+    Error(ErrorInfo),
+    /// the job of the turn with this id was cancelled by the user
+    Cancelled(String),
     Complete,
 }
 
@@ -40,6 +61,8 @@ pub(crate) struct ChatInputSkeletonEvent {
     datetime: String,
     avatar: String,
     name: String,
+    // rendered input, empty while still transcribing
+    content: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -53,8 +76,9 @@ pub(crate) struct ChatInputEvent {
 #[template(path = "events/chat_reply_skeleton.html.j2")]
 pub(crate) struct ChatReplySkeletonEvent {
     id: String,
-    avatar: String, // /public/images/ava-small.png
-    name: String,   // Ava
+    avatar: String,  // /public/images/ava-small.png
+    name: String,    // Ava
+    content: String, // rendered reply, empty while still thinking
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -64,12 +88,22 @@ pub(crate) struct ChatReplyEvent {
     data: ChatReplyData,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, From)]
+/// A chunk of streamed completion text, appended to the reply node as plain text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChatReplyDeltaEvent {
+    id: String,
+    delta: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, From, AsRefStr)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 pub(crate) enum ChatReplyData {
     Speech(SpeechResult),
     Image(DrawImageResult),
     Markdown(WriteCodeResult),
+    Refusal(RefusalResult),
+    VoiceSettings(VoiceSettingsResult),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -77,41 +111,63 @@ pub(crate) enum ChatReplyData {
 pub(crate) struct SpeechResult {
     text: String,
     url: String,
+    // replies made before formats could be picked are mp3
+    #[serde(default)]
+    format: AudioFormat,
+    // only a reply made just now plays by itself, not the history or
+    // replies replayed after a reconnect
+    #[serde(skip)]
+    autoplay: bool,
+}
+
+/// Shown in place of a reply, or the rest of it, blocked by the content filter.
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "blocks/refusal.html.j2")]
+pub(crate) struct RefusalResult {
+    message: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, EnumString, Display)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub(crate) enum AssistantStep {
+    #[strum(serialize = "Waiting for other requests")]
+    Queued,
     #[strum(serialize = "Uploading audio")]
     UploadAudio,
     #[strum(serialize = "Transcribing audio")]
     Transcription,
     #[strum(serialize = "Thinking hard")]
     Thinking,
-    #[strum(serialize = "Organizing answer")]
-    ChatCompletion,
-    #[strum(serialize = "Drawing image")]
-    DrawImage,
-    #[strum(serialize = "Writing code")]
-    WriteCode,
     #[strum(serialize = "Generating speech")]
     Speech,
 }
 
+pub(crate) fn current_datetime() -> String {
+    OffsetDateTime::now_utc()
+        .to_offset(offset!(+08:00:00))
+        .format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second]"
+        ))
+        .unwrap()
+}
+
 impl ChatInputSkeletonEvent {
     pub fn new(id: impl Into<String>) -> Self {
-        let datetime = OffsetDateTime::now_utc()
-            .to_offset(offset!(+08:00:00))
-            .format(format_description!(
-                "[year]-[month]-[day] [hour]:[minute]:[second]"
-            ))
-            .unwrap();
+        Self::new_with_content(id, current_datetime(), "")
+    }
+
+    pub fn new_with_content(
+        id: impl Into<String>,
+        datetime: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
         Self {
             id: id.into(),
-            datetime,
+            datetime: datetime.into(),
             avatar: "https://i.pravatar.cc/128".to_string(),
             name: "User".to_string(),
+            content: content.into(),
         }
     }
 }
@@ -127,10 +183,15 @@ impl ChatInputEvent {
 
 impl ChatReplySkeletonEvent {
     pub fn new(id: impl Into<String>) -> Self {
+        Self::new_with_content(id, "")
+    }
+
+    pub fn new_with_content(id: impl Into<String>, content: impl Into<String>) -> Self {
         Self {
             id: id.into(),
             avatar: "./public/images/ava-small.png".to_string(),
             name: "Ava".to_string(),
+            content: content.into(),
         }
     }
 }
@@ -144,16 +205,92 @@ impl ChatReplyEvent {
     }
 }
 
+impl ChatReplyDeltaEvent {
+    pub fn new(id: impl Into<String>, delta: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            delta: delta.into(),
+        }
+    }
+}
+
+impl ChatReplyData {
+    /// Url of the generated asset backing this reply, if any.
+    pub(crate) fn asset_url(&self) -> Option<&str> {
+        match self {
+            ChatReplyData::Speech(v) if !v.url.is_empty() => Some(&v.url),
+            ChatReplyData::Image(v) if !v.url.is_empty() => Some(&v.url),
+            _ => None,
+        }
+    }
+
+    /// The reply shown without playing its audio.
+    pub(crate) fn without_autoplay(mut self) -> Self {
+        if let ChatReplyData::Speech(v) = &mut self {
+            v.autoplay = false;
+        }
+        self
+    }
+
+    /// What the model gets to see of this reply in the conversation.
+    pub(crate) fn model_content(&self) -> String {
+        match self {
+            ChatReplyData::Speech(v) => v.text.clone(),
+            ChatReplyData::Image(v) => {
+                serde_json::json!({"url": v.url, "prompt": v.prompt}).to_string()
+            }
+            ChatReplyData::Markdown(v) => v.source.clone(),
+            ChatReplyData::Refusal(v) => v.message.clone(),
+            ChatReplyData::VoiceSettings(v) => v.text(),
+        }
+    }
+}
+
+impl RefusalResult {
+    pub(crate) fn new(lang: Lang) -> Self {
+        Self {
+            message: ErrorKind::ContentFilter.message(lang).to_string(),
+        }
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        &self.message
+    }
+}
+
 impl SpeechResult {
-    fn new(text: impl Into<String>, url: impl Into<String>) -> Self {
+    pub(crate) fn new(
+        text: impl Into<String>,
+        url: impl Into<String>,
+        format: AudioFormat,
+    ) -> Self {
         Self {
             text: text.into(),
             url: url.into(),
+            format,
+            autoplay: true,
         }
     }
 
-    fn new_text_only(text: impl Into<String>) -> Self {
-        Self::new(text, "".to_string())
+    pub(crate) fn new_text_only(text: impl Into<String>) -> Self {
+        Self::new(text, "".to_string(), AudioFormat::default())
+    }
+
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl AssistantEvent {
+    /// The event as sent again to a reconnecting event stream.
+    pub(crate) fn replayed(self) -> Self {
+        match self {
+            AssistantEvent::Reply(mut v) => {
+                v.data = v.data.without_autoplay();
+                v.into()
+            }
+            event => event,
+        }
     }
 }
 
@@ -165,6 +302,7 @@ impl From<AssistantEvent> for String {
             AssistantEvent::Input(v) => v.into(),
             AssistantEvent::ReplySkeleton(v) => v.into(),
             AssistantEvent::Reply(v) => v.into(),
+            AssistantEvent::ReplyDelta(v) => v.into(),
         }
     }
 }
@@ -198,3 +336,9 @@ impl From<ChatReplyEvent> for String {
         event.render().unwrap()
     }
 }
+
+impl From<ChatReplyDeltaEvent> for String {
+    fn from(event: ChatReplyDeltaEvent) -> Self {
+        event.delta
+    }
+}
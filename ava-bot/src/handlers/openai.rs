@@ -0,0 +1,367 @@
+use super::agent::{run_turn, AgentSink, ToolOutput};
+use super::{app_state, AssistantEvent, ChatReplyData};
+use crate::error::{ErrorInfo, ErrorKind, Lang};
+use crate::events::EventChannel;
+use crate::tools::ToolContext;
+use crate::AppState;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use llm_sdk::{AssistantMessage, ChatCompletionMessage, ToolCall};
+use salvo::http::StatusCode;
+use salvo::prelude::{Json, SseKeepAlive, Text};
+use salvo::sse::SseEvent;
+use salvo::{handler, Depot, Request, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Model name Ava is listed as, any requested model is served by Ava anyway.
+const MODEL_ID: &str = "ava";
+/// Assets generated for API clients are kept under this device id.
+const API_DEVICE_ID: &str = "api";
+// deltas of a streaming tool are buffered here until they're forwarded
+const MAX_DELTAS: usize = 1024;
+
+/// Request of the OpenAI chat completions API. Only the fields Ava cares
+/// about, `tools` and sampling parameters of the client are ignored.
+#[derive(Debug, Deserialize)]
+struct ChatCompletionsRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<OpenAiMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<OpenAiContent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAiContent {
+    Text(String),
+    Parts(Vec<OpenAiContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiContentPart {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+/// One completion in the OpenAI format, shared by whole and chunked responses.
+struct Completion {
+    id: String,
+    model: String,
+    created: i64,
+}
+
+#[handler]
+pub async fn models_handler(res: &mut Response) {
+    res.render(Json(json!({
+        "object": "list",
+        "data": [{"id": MODEL_ID, "object": "model", "created": 0, "owned_by": "ava-bot"}],
+    })));
+}
+
+/// `/v1/chat/completions`: the last message must be from the user, the ones
+/// before it are the history. The turn runs through Ava's tool routing and
+/// every reply the tools make is joined into the assistant message.
+#[handler]
+pub async fn chat_completions_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let Ok(state) = app_state(depot) else {
+        return render_error(
+            res,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Internal.as_ref(),
+            "app state not found",
+        );
+    };
+    let body: ChatCompletionsRequest = match req.parse_json().await {
+        Ok(v) => v,
+        Err(e) => return render_error(res, StatusCode::BAD_REQUEST, "bad_input", e),
+    };
+    let (history, input) = match split_messages(body.messages) {
+        Ok(v) => v,
+        Err(e) => return render_error(res, StatusCode::BAD_REQUEST, "bad_input", e),
+    };
+    let completion = Completion {
+        id: format!("chatcmpl-{}", Uuid::new_v4()),
+        model: body.model.unwrap_or_else(|| MODEL_ID.to_string()),
+        created: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+    info!("api completion {} (stream: {})", completion.id, body.stream);
+    let lang = Lang::from_request(req);
+
+    if !body.stream {
+        let content = Mutex::new(String::new());
+        let emit = |text: String| content.lock().unwrap().push_str(&text);
+        match run(&state, history, input, lang, &emit).await {
+            Ok(_) => res.render(Json(completion.message(content.into_inner().unwrap()))),
+            Err(e) => {
+                let info = ErrorInfo::new(&e, lang);
+                warn!(
+                    "api completion {} failed ({}): {e:#}",
+                    completion.id, info.code
+                );
+                render_error(res, info.code.status(), info.code.as_ref(), info.message)
+            }
+        }
+        return;
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let _ = tx.send(completion.chunk(json!({"role": "assistant"}), None));
+        let emit = |text: String| {
+            let _ = tx.send(completion.chunk(json!({ "content": text }), None));
+        };
+        let last = match run(&state, history, input, lang, &emit).await {
+            Ok(_) => completion.chunk(json!({}), Some("stop")),
+            Err(e) => {
+                let info = ErrorInfo::new(&e, lang);
+                warn!(
+                    "api completion {} failed ({}): {e:#}",
+                    completion.id, info.code
+                );
+                error_body(info.code.as_ref(), info.message)
+            }
+        };
+        let _ = tx.send(last);
+        let _ = tx.send("[DONE]".to_string());
+    });
+
+    let stream = UnboundedReceiverStream::new(rx)
+        .map(|data| Ok::<_, Infallible>(SseEvent::default().text(data)));
+    SseKeepAlive::new(stream).stream(res);
+}
+
+/// Run the turn through the agent loop, passing the text of every reply to
+/// `emit` as it's made.
+async fn run(
+    state: &AppState,
+    history: Vec<ChatCompletionMessage>,
+    input: String,
+    lang: Lang,
+    emit: &(dyn Fn(String) + Send + Sync),
+) -> anyhow::Result<()> {
+    let sink = ApiSink {
+        state,
+        history: &history,
+        lang,
+        emit,
+        // API clients get no progress signals, retries included
+        signals: EventChannel::new(MAX_DELTAS),
+    };
+    run_turn(state, &history, &input, &sink).await?;
+    Ok(())
+}
+
+/// A turn of an API client: every reply becomes text of the completion.
+struct ApiSink<'a> {
+    state: &'a AppState,
+    history: &'a [ChatCompletionMessage],
+    lang: Lang,
+    emit: &'a (dyn Fn(String) + Send + Sync),
+    signals: EventChannel,
+}
+
+#[async_trait]
+impl AgentSink for ApiSink<'_> {
+    fn signals(&self) -> &EventChannel {
+        &self.signals
+    }
+
+    async fn answer(&self, text: String, filtered: bool) -> anyhow::Result<String> {
+        (self.emit)(text.clone());
+        if filtered {
+            return Err(ErrorKind::ContentFilter.error("reply blocked by the content filter"));
+        }
+        Ok(text)
+    }
+
+    /// Tools run one after another so the text they stream doesn't interleave.
+    async fn call_tools(&self, calls: &[ToolCall]) -> Vec<anyhow::Result<ToolOutput>> {
+        let mut outputs = Vec::with_capacity(calls.len());
+        for (i, call) in calls.iter().enumerate() {
+            if i > 0 {
+                (self.emit)("\n\n".to_string());
+            }
+            outputs.push(self.call_tool(call).await);
+        }
+        outputs
+    }
+}
+
+impl ApiSink<'_> {
+    /// Run a tool, forwarding the deltas it streams.
+    async fn call_tool(&self, call: &ToolCall) -> anyhow::Result<ToolOutput> {
+        let tool = self
+            .state
+            .tools
+            .get(&call.function.name)
+            .ok_or_else(|| anyhow!("no proper tool found for {}", call.function.name))?;
+
+        let event_sender = EventChannel::new(MAX_DELTAS);
+        let (_, mut events) = event_sender.subscribe(None);
+        let ctx = ToolContext {
+            state: self.state,
+            event_sender: &event_sender,
+            owner_id: API_DEVICE_ID,
+            device_id: None,
+            audio_format: None,
+            lang: self.lang,
+            reply_id: &call.id,
+            history: self.history,
+        };
+        let mut streamed = String::new();
+        let mut lagged = false;
+        let mut forward = |event: AssistantEvent| {
+            if let AssistantEvent::ReplyDelta(v) = event {
+                streamed.push_str(&v.delta);
+                (self.emit)(v.delta);
+            }
+        };
+
+        let call_tool = tool.call(&ctx, &call.function.arguments);
+        tokio::pin!(call_tool);
+        let reply = loop {
+            tokio::select! {
+                ret = &mut call_tool => break ret?,
+                event = events.recv(), if !lagged => match event {
+                    Ok(v) => forward(v.event),
+                    Err(RecvError::Lagged(n)) => {
+                        // the rest is taken from the final reply below
+                        warn!("api completion missed {n} deltas of {}", tool.name());
+                        lagged = true;
+                    }
+                    Err(RecvError::Closed) => lagged = true,
+                },
+            }
+        };
+        if !lagged {
+            while let Ok(v) = events.try_recv() {
+                forward(v.event);
+            }
+        }
+
+        let text = reply_text(self.state, &reply);
+        match text.strip_prefix(&streamed) {
+            Some(rest) => {
+                if !rest.is_empty() {
+                    (self.emit)(rest.to_string());
+                }
+            }
+            None => (self.emit)(format!("\n\n{text}")),
+        }
+        Ok(ToolOutput {
+            content: reply.model_content(),
+            done: tool.ends_turn(),
+        })
+    }
+}
+
+/// The reply as text for API clients, assets are linked by their full url.
+fn reply_text(state: &AppState, reply: &ChatReplyData) -> String {
+    match reply {
+        ChatReplyData::Speech(v) => v.text.clone(),
+        ChatReplyData::Image(v) => format!(
+            "![{}]({}{})",
+            v.prompt,
+            state.config.public_url(),
+            v.url.trim_start_matches('.')
+        ),
+        ChatReplyData::Markdown(v) => v.source.clone(),
+        ChatReplyData::Refusal(v) => v.message().to_string(),
+        ChatReplyData::VoiceSettings(v) => v.text(),
+    }
+}
+
+fn split_messages(
+    messages: Vec<OpenAiMessage>,
+) -> anyhow::Result<(Vec<ChatCompletionMessage>, String)> {
+    let mut history: Vec<_> = messages
+        .into_iter()
+        .filter_map(|message| {
+            let content = match message.content? {
+                OpenAiContent::Text(text) => text,
+                OpenAiContent::Parts(parts) => parts
+                    .into_iter()
+                    .filter_map(|part| part.text)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            };
+            Some((message.role, content))
+        })
+        .collect();
+
+    let input = match history.pop() {
+        Some((role, content)) if role == "user" => content,
+        _ => bail!("the last message must be from the user"),
+    };
+    let history = history
+        .into_iter()
+        .filter_map(|(role, content)| match role.as_str() {
+            "system" | "developer" => Some(ChatCompletionMessage::new_system(content, "")),
+            "user" => Some(ChatCompletionMessage::new_user(content, "")),
+            "assistant" => Some(ChatCompletionMessage::Assistant(AssistantMessage {
+                content: Some(content),
+                name: None,
+                tool_calls: vec![],
+            })),
+            // tools of the client are never offered, so are their results
+            _ => None,
+        })
+        .collect();
+    Ok((history, input))
+}
+
+/// `code` is an `ErrorKind` code.
+fn render_error(res: &mut Response, status: StatusCode, code: &str, message: impl ToString) {
+    res.status_code(status);
+    res.render(Text::Json(error_body(code, message)));
+}
+
+fn error_body(code: &str, message: impl ToString) -> String {
+    json!({"error": {"message": message.to_string(), "type": "ava_error", "code": code}})
+        .to_string()
+}
+
+impl Completion {
+    fn message(&self, content: String) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop",
+            }],
+        })
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> String {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+        .to_string()
+    }
+}
@@ -0,0 +1,51 @@
+use super::app_state;
+use crate::error::{AppError, ErrorKind};
+use crate::session::session;
+use crate::voice::{AudioFormat, Voice, VoiceModel, VoiceSettings};
+use salvo::prelude::Redirect;
+use salvo::{handler, Depot, Request, Response};
+use tracing::info;
+
+/// Save the voice settings of the device from the settings panel.
+#[handler]
+pub async fn voice_settings_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let voice = req.form::<String>("voice").await.unwrap_or_default();
+    let model = req.form::<String>("model").await.unwrap_or_default();
+    let speed = req.form::<f32>("speed").await;
+    // empty for whatever the browser plays
+    let format = req.form::<String>("format").await.unwrap_or_default();
+    let settings =
+        VoiceSettings {
+            voice: voice
+                .parse::<Voice>()
+                .map_err(|_| ErrorKind::BadInput.error(format!("unknown voice {voice:?}")))?,
+            speed: speed.ok_or_else(|| ErrorKind::BadInput.error("speed must be a number"))?,
+            model: model
+                .parse::<VoiceModel>()
+                .map_err(|_| ErrorKind::BadInput.error(format!("unknown voice model {model:?}")))?,
+            format: match format.as_str() {
+                "" => None,
+                v => Some(v.parse::<AudioFormat>().map_err(|_| {
+                    ErrorKind::BadInput.error(format!("unknown audio format {v:?}"))
+                })?),
+            },
+        };
+    settings.validate()?;
+
+    let state = app_state(depot)?;
+    let session = session(depot)?;
+    state
+        .store
+        .save_voice_settings(&session.device_id, &settings)?;
+    info!(
+        "device {} changed voice to {}",
+        session.device_id,
+        settings.summary()
+    );
+    res.render(Redirect::see_other("/"));
+    Ok(())
+}
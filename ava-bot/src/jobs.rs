@@ -0,0 +1,114 @@
+use crate::error::ErrorKind;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::Arc;
+use strum::{AsRefStr, Display, EnumString};
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+
+/// Every assistant request runs as a job in the background, so it finishes
+/// even when the request or the event stream of the browser is cut off.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Job {
+    pub(crate) id: String,
+    #[serde(skip)]
+    pub(crate) owner_id: String,
+    pub(crate) state: JobState,
+    /// why the job failed, for the user
+    pub(crate) error: Option<String>,
+    /// stable code of the error, e.g. `rate_limited`
+    pub(crate) error_code: Option<ErrorKind>,
+    pub(crate) created_at: String,
+    pub(crate) updated_at: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, EnumString, Display, AsRefStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+/// Limits how many jobs run at once, the others stay queued, and lets
+/// unfinished jobs be cancelled. Whoever takes the cancel handle of a job,
+/// `cancel` or `finish`, decides how it ends, so a job finishing while it's
+/// cancelled ends one way only.
+pub(crate) struct Jobs {
+    permits: Semaphore,
+    cancels: DashMap<String, Arc<Notify>>,
+}
+
+impl Jobs {
+    pub(crate) fn new(max_running: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max_running),
+            cancels: DashMap::new(),
+        }
+    }
+
+    /// Track a new job until `finish`, the job stops once the returned
+    /// `Notify` fires.
+    pub(crate) fn register(&self, id: &str) -> Arc<Notify> {
+        let cancel = Arc::new(Notify::new());
+        self.cancels.insert(id.to_string(), cancel.clone());
+        cancel
+    }
+
+    /// Stop the job, false if it has finished already.
+    pub(crate) fn cancel(&self, id: &str) -> bool {
+        match self.cancels.remove(id) {
+            // notify_one keeps the permit when the job isn't waiting yet
+            Some((_, cancel)) => {
+                cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop tracking the job, false if it was cancelled before it finished:
+    /// it ends as cancelled, whatever it got done.
+    pub(crate) fn finish(&self, id: &str) -> bool {
+        self.cancels.remove(id).is_some()
+    }
+
+    /// Wait until the job may run, it runs until the permit is dropped.
+    pub(crate) async fn start(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("job semaphore is never closed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_and_finish_race_has_one_winner() {
+        let jobs = Jobs::new(1);
+        jobs.register("a");
+        assert!(jobs.cancel("a"));
+        assert!(!jobs.cancel("a"));
+        assert!(!jobs.finish("a"));
+
+        jobs.register("b");
+        assert!(jobs.finish("b"));
+        assert!(!jobs.cancel("b"));
+    }
+
+    #[tokio::test]
+    async fn cancel_before_the_job_waits_still_stops_it() {
+        let jobs = Jobs::new(1);
+        let cancel = jobs.register("a");
+        assert!(jobs.cancel("a"));
+        // the permit is kept until the job waits on it
+        tokio::time::timeout(std::time::Duration::from_secs(1), cancel.notified())
+            .await
+            .unwrap();
+    }
+}
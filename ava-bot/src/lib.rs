@@ -1,55 +1,183 @@
-use crate::handlers::AssistantEvent;
+use crate::assets::ASSETS_DIR;
+use crate::events::EventHub;
+use crate::jobs::Jobs;
+use crate::llm::Upstream;
+use crate::memory::Memory;
+use crate::oidc::OidcClient;
+use crate::pairing::Pairings;
+use crate::session::Sessions;
+use crate::tools::{register_mcp_servers, ToolRegistry};
 use clap::Parser;
-use dashmap::DashMap;
-use llm_sdk::{LlmSDK};
-use once_cell::sync::Lazy;
-use std::env;
 use std::path::{Path, PathBuf};
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod assets;
+mod config;
 mod error;
+mod events;
 pub mod handlers;
+mod jobs;
+mod llm;
+mod mcp;
+mod memory;
+mod oidc;
+mod pairing;
+mod session;
+mod store;
+pub mod tls;
 mod tools;
+mod voice;
+
+pub use assets::{assets_handler, AssetTokens};
+pub use config::Config;
+pub use events::{EventHub, EventStats};
+pub use llm::{Delta, DeltaStream, LlmBackend, OpenAiBackend};
+pub use mcp::{mcp_handler, serve_stdio as serve_mcp_stdio};
+pub use session::{issue_session, require_api_key, require_session};
+pub use store::Store;
+pub use voice::{AudioFormat, Voice, VoiceModel};
 
 #[derive(Debug, Parser)]
 #[clap(name = "ava")]
 pub struct Args {
-    #[clap(short, long, default_value = "8080")]
-    pub port: u16,
-    #[clap(short, long, default_value = ".certs")]
-    pub cert_path: String,
-}
-
-pub static LLM_SDK: Lazy<LlmSDK> = Lazy::new(|| {
-    let sdk = LlmSDK::new_with_base_url(
-        env::var("OPENAI_API_KEY").unwrap(),
-        "https://api.xty.app/v1",
-    );
-    sdk
-    /*LlmSDK::new_with_base_url(
-        "sk-".to_string(),
-        "https://api.openai.com/v1",
-    )*/
-});
-
-pub(crate) static EVENTS: Lazy<DashMap<String, broadcast::Sender<AssistantEvent>>> =
-    Lazy::new(DashMap::new);
-
-pub fn audio_path(device_id: &str, name: &str) -> PathBuf {
-    Path::new("./tmp/ava-bot/audio")
-        .join(device_id)
-        .join(format!("{}.mp3", name))
-}
-pub fn audio_url(device_id: &str, name: &str) -> String {
-    format!("./assets/audio/{}/{}.mp3", device_id, name)
-}
-
-pub fn image_path(device_id: &str, name: &str) -> PathBuf {
-    Path::new("./tmp/ava-bot/image")
-        .join(device_id)
+    /// path of the TOML config file
+    #[clap(short = 'f', long, env = "AVA_CONFIG", default_value = Args::DEFAULT_CONFIG)]
+    pub config: String,
+    #[clap(short, long, env = "AVA_PORT")]
+    pub port: Option<u16>,
+    /// directory holding cert.pem and key.pem, served over https when present
+    #[clap(short, long, env = "AVA_CERT_PATH")]
+    pub cert_path: Option<String>,
+    /// plain http port redirecting to https
+    #[clap(long, env = "AVA_REDIRECT_PORT")]
+    pub redirect_port: Option<u16>,
+    /// path of the sqlite database keeping chat history
+    #[clap(long, env = "AVA_DB_PATH")]
+    pub db_path: Option<String>,
+    /// url Ava is reached at, e.g. behind a proxy
+    #[clap(long, env = "AVA_PUBLIC_URL")]
+    pub public_url: Option<String>,
+    /// base url of the OpenAI compatible API
+    #[clap(long, env = "AVA_BASE_URL")]
+    pub base_url: Option<String>,
+    #[clap(long, env = "OPENAI_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// chat completion model, e.g. gpt-4-1106-preview
+    #[clap(long, env = "AVA_MODEL")]
+    pub model: Option<String>,
+    /// voice used for speech, e.g. alloy
+    #[clap(long, env = "AVA_VOICE")]
+    pub voice: Option<String>,
+    #[clap(long, env = "AVA_WHISPER_PROMPT")]
+    pub whisper_prompt: Option<String>,
+    /// system prompt picking the tool to handle the input
+    #[clap(long, env = "AVA_PROMPT_TOOL")]
+    pub prompt_tool: Option<String>,
+    /// system prompt of the answer tool
+    #[clap(long, env = "AVA_PROMPT_ANSWER")]
+    pub prompt_answer: Option<String>,
+    /// system prompt of the write code tool
+    #[clap(long, env = "AVA_PROMPT_WRITE_CODE")]
+    pub prompt_write_code: Option<String>,
+    /// max number of past turns kept as conversation memory per device
+    #[clap(long, env = "AVA_MAX_TURNS")]
+    pub max_turns: Option<usize>,
+    /// max estimated tokens of conversation memory per device
+    #[clap(long, env = "AVA_MAX_TOKENS")]
+    pub max_tokens: Option<usize>,
+    /// key signing the device cookies
+    #[clap(long, env = "AVA_SESSION_SECRET", hide_env_values = true)]
+    pub session_secret: Option<String>,
+    /// serve Ava's tools as an MCP server over stdio instead of the web app
+    #[clap(long)]
+    pub mcp_stdio: bool,
+}
+
+impl Args {
+    pub const DEFAULT_CONFIG: &'static str = "ava.toml";
+}
+
+/// Shared services injected into every handler through the `Depot`.
+#[derive(Clone)]
+pub struct AppState {
+    pub(crate) config: Arc<Config>,
+    pub(crate) llm: Arc<Upstream>,
+    pub(crate) tools: Arc<ToolRegistry>,
+    pub(crate) sessions: Arc<Sessions>,
+    pub(crate) oidc: Option<Arc<OidcClient>>,
+    pub(crate) jobs: Arc<Jobs>,
+    pub(crate) events: Arc<EventHub>,
+    pub(crate) memory: Arc<Memory>,
+    pub(crate) store: Arc<Store>,
+    pub(crate) pairings: Arc<Pairings>,
+    pub(crate) assets: Arc<AssetTokens>,
+}
+
+impl AppState {
+    pub fn new(config: Config, store: Store, llm: impl LlmBackend) -> Self {
+        let sessions = Sessions::new(config.session_secret());
+        let assets = AssetTokens::new(config.session_secret(), config.assets.token_ttl);
+        let oidc = config
+            .oidc
+            .clone()
+            .map(|oidc| Arc::new(OidcClient::new(oidc, config.oidc_redirect_url())));
+        let jobs = Jobs::new(config.agent.max_jobs);
+        let events = EventHub::new(Duration::from_secs(config.events.idle_ttl));
+        let memory = Memory::new(config.memory.max_turns, config.memory.max_tokens);
+        let llm = Upstream::new(&config.llm, config.api_key(), llm);
+        Self {
+            config: Arc::new(config),
+            llm: Arc::new(llm),
+            tools: Arc::new(ToolRegistry::default()),
+            sessions: Arc::new(sessions),
+            oidc,
+            jobs: Arc::new(jobs),
+            events: Arc::new(events),
+            memory: Arc::new(memory),
+            store: Arc::new(store),
+            pairings: Arc::new(Pairings::new()),
+            assets: Arc::new(assets),
+        }
+    }
+
+    /// The event channels, for the task evicting idle ones.
+    pub fn events(&self) -> Arc<EventHub> {
+        self.events.clone()
+    }
+
+    /// Connect to the MCP servers in the config and offer their tools to the model.
+    pub async fn connect_mcp_servers(mut self) -> Self {
+        let mut tools = ToolRegistry::default();
+        register_mcp_servers(&mut tools, &self.config.mcp.servers).await;
+        self.tools = Arc::new(tools);
+        self
+    }
+}
+
+pub fn audio_path(owner_id: &str, name: &str, format: AudioFormat) -> PathBuf {
+    Path::new(ASSETS_DIR)
+        .join("audio")
+        .join(owner_id)
+        .join(format!("{}.{}", name, format.extension()))
+}
+
+pub fn audio_url(assets: &AssetTokens, owner_id: &str, name: &str, format: AudioFormat) -> String {
+    assets.url(&format!(
+        "audio/{}/{}.{}",
+        owner_id,
+        name,
+        format.extension()
+    ))
+}
+
+pub fn image_path(owner_id: &str, name: &str) -> PathBuf {
+    Path::new(ASSETS_DIR)
+        .join("image")
+        .join(owner_id)
         .join(format!("{}.png", name))
 }
 
-pub fn image_url(device_id: &str, name: &str) -> String {
-    format!("./assets/image/{}/{}.png", device_id, name)
+pub fn image_url(assets: &AssetTokens, owner_id: &str, name: &str) -> String {
+    assets.url(&format!("image/{}/{}.png", owner_id, name))
 }
@@ -0,0 +1,108 @@
+use super::{Delta, DeltaStream, LlmBackend};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use llm_sdk::{
+    ChatCompleteModel, ChatCompletionRequest, ChatCompletionResponse, CreateImageRequest,
+    CreateImageResponse, SpeechRequest, WhisperRequest, WhisperResponse,
+};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Backend answering chat completions from a script, in order, and keeping
+/// the requests it got. Clones share both.
+#[derive(Clone, Default)]
+pub(crate) struct FakeBackend {
+    responses: Arc<Mutex<VecDeque<ChatCompletionResponse>>>,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl FakeBackend {
+    /// Answer the next completion with `content`, ending for `finish_reason`,
+    /// e.g. "stop" or "length".
+    pub(crate) fn reply(self, content: &str, finish_reason: &str) -> Self {
+        self.push(
+            json!({"role": "assistant", "content": content}),
+            finish_reason,
+        )
+    }
+
+    /// Answer the next completion with a call of the tool `name`.
+    pub(crate) fn tool_call(self, name: &str, arguments: &str) -> Self {
+        let n = self.responses.lock().unwrap().len();
+        let message = json!({
+            "role": "assistant",
+            "tool_calls": [{
+                "id": format!("call_{n}"),
+                "type": "function",
+                "function": {"name": name, "arguments": arguments},
+            }],
+        });
+        self.push(message, "tool_calls")
+    }
+
+    /// The chat completion requests made so far, as json.
+    pub(crate) fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn push(self, message: Value, finish_reason: &str) -> Self {
+        let res = json!({
+            "id": "chatcmpl-fake",
+            "object": "chat.completion",
+            "created": 0,
+            "model": ChatCompleteModel::default(),
+            "system_fingerprint": null,
+            "choices": [{"index": 0, "finish_reason": finish_reason, "message": message}],
+            "usage": {"prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0},
+        });
+        let res = serde_json::from_value(res).expect("scripted response is valid");
+        self.responses.lock().unwrap().push_back(res);
+        self
+    }
+
+    fn next(&self, req: &ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push(serde_json::to_value(req)?);
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| anyhow!("no scripted response left"))
+    }
+}
+
+#[async_trait]
+impl LlmBackend for FakeBackend {
+    async fn whisper(&self, _req: WhisperRequest) -> Result<WhisperResponse> {
+        bail!("whisper is not scripted")
+    }
+
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+        self.next(&req)
+    }
+
+    /// The scripted content as a single delta.
+    async fn chat_completion_stream(&self, req: ChatCompletionRequest) -> Result<DeltaStream> {
+        let choice = self
+            .next(&req)?
+            .choices
+            .pop()
+            .ok_or_else(|| anyhow!("scripted response has no choice"))?;
+        let delta = Delta {
+            content: choice.message.content.unwrap_or_default(),
+            finish_reason: Some(choice.finish_reason),
+        };
+        Ok(Box::pin(tokio_stream::once(Ok(delta))))
+    }
+
+    async fn speech(&self, _req: SpeechRequest) -> Result<Vec<u8>> {
+        Ok(b"fake audio".to_vec())
+    }
+
+    async fn create_image(&self, _req: CreateImageRequest) -> Result<CreateImageResponse> {
+        bail!("create_image is not scripted")
+    }
+}
@@ -0,0 +1,176 @@
+#[cfg(test)]
+mod fake;
+mod stream;
+mod upstream;
+
+use crate::error::ErrorKind;
+use anyhow::Result;
+use async_trait::async_trait;
+use llm_sdk::{
+    ChatCompletionRequest, ChatCompletionResponse, CreateImageRequest, CreateImageResponse, LlmSDK,
+    SpeechRequest, WhisperRequest, WhisperResponse,
+};
+
+#[cfg(test)]
+pub(crate) use fake::FakeBackend;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::fmt;
+use stream::ChatStream;
+pub use stream::{Delta, DeltaStream};
+pub(crate) use upstream::{Operation, Upstream};
+
+/// The AI operations Ava relies on. Handlers only talk to this trait, through
+/// `Upstream`, so the provider can be swapped, or replaced by a fake in tests.
+#[async_trait]
+pub trait LlmBackend: Send + Sync + 'static {
+    async fn whisper(&self, req: WhisperRequest) -> Result<WhisperResponse>;
+
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse>;
+
+    /// Same as `chat_completion`, but yields deltas as they arrive.
+    async fn chat_completion_stream(&self, req: ChatCompletionRequest) -> Result<DeltaStream>;
+
+    async fn speech(&self, req: SpeechRequest) -> Result<Vec<u8>>;
+
+    async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse>;
+}
+
+/// A call the provider answered with an error status, with the code of the
+/// OpenAI error body, e.g. `content_policy_violation`.
+#[derive(Debug)]
+pub(crate) struct UpstreamError {
+    pub(crate) status: StatusCode,
+    pub(crate) code: Option<String>,
+    pub(crate) message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: ErrorBodyError,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBodyError {
+    message: String,
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    code: Option<String>,
+}
+
+/// Default backend for OpenAI compatible APIs.
+pub struct OpenAiBackend {
+    sdk: LlmSDK,
+    stream: ChatStream,
+}
+
+impl OpenAiBackend {
+    pub fn new(token: impl Into<String>, base_url: impl Into<String>) -> Self {
+        let token = token.into();
+        let base_url = base_url.into();
+        Self {
+            sdk: LlmSDK::new_with_base_url(token.clone(), &base_url),
+            stream: ChatStream::new(token, base_url),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    async fn whisper(&self, req: WhisperRequest) -> Result<WhisperResponse> {
+        self.sdk.whisper(req).await.map_err(sdk_error)
+    }
+
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+        self.sdk.chat_completion(req).await.map_err(sdk_error)
+    }
+
+    async fn chat_completion_stream(&self, req: ChatCompletionRequest) -> Result<DeltaStream> {
+        self.stream.chat_completion(&req).await
+    }
+
+    async fn speech(&self, req: SpeechRequest) -> Result<Vec<u8>> {
+        let data = self.sdk.speech(req).await.map_err(sdk_error)?;
+        Ok(data.to_vec())
+    }
+
+    async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse> {
+        self.sdk.create_image(req).await.map_err(sdk_error)
+    }
+}
+
+impl UpstreamError {
+    /// The error of a response, `body` as sent by the provider.
+    pub(crate) fn new(status: StatusCode, body: &str) -> Self {
+        match serde_json::from_str::<ErrorBody>(body) {
+            Ok(body) => Self {
+                status,
+                code: body.error.code,
+                message: body.error.message,
+            },
+            Err(_) => Self {
+                status,
+                code: None,
+                message: body.to_string(),
+            },
+        }
+    }
+
+    /// What the failure means for the user. A rejected key, or an unknown
+    /// model, is on Ava, not on the request.
+    pub(crate) fn kind(&self) -> ErrorKind {
+        match (self.status.as_u16(), self.code.as_deref()) {
+            (_, Some("content_policy_violation" | "content_filter")) => ErrorKind::ContentFilter,
+            (429, _) => ErrorKind::RateLimited,
+            (408, _) => ErrorKind::UpstreamTimeout,
+            (401 | 403 | 404, _) => ErrorKind::Internal,
+            (400..=499, _) => ErrorKind::BadInput,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// llm-sdk fails a call the provider answered with an error status as
+/// `API failed: {body}`, without the status. It's told by the type of the
+/// OpenAI error in the body instead; errors the provider didn't answer,
+/// e.g. it can't be reached, are left as they are.
+fn sdk_error(err: anyhow::Error) -> anyhow::Error {
+    let Some(body) = err
+        .to_string()
+        .strip_prefix("API failed: ")
+        .map(str::to_string)
+    else {
+        return err;
+    };
+    let Ok(ErrorBody { error }) = serde_json::from_str::<ErrorBody>(&body) else {
+        return err;
+    };
+    let status = match (error.kind.as_deref(), error.code.as_deref()) {
+        (_, Some("rate_limit_exceeded" | "insufficient_quota")) => StatusCode::TOO_MANY_REQUESTS,
+        (_, Some("invalid_api_key")) => StatusCode::UNAUTHORIZED,
+        (_, Some("model_not_found")) => StatusCode::NOT_FOUND,
+        (Some("invalid_request_error"), _) => StatusCode::BAD_REQUEST,
+        (Some("authentication_error"), _) => StatusCode::UNAUTHORIZED,
+        (Some("permission_error"), _) => StatusCode::FORBIDDEN,
+        (Some("not_found_error"), _) => StatusCode::NOT_FOUND,
+        (Some("rate_limit_error"), _) => StatusCode::TOO_MANY_REQUESTS,
+        (Some("server_error" | "api_error"), _) => StatusCode::INTERNAL_SERVER_ERROR,
+        (Some("overloaded_error"), _) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => return err,
+    };
+    UpstreamError {
+        status,
+        code: error.code,
+        message: error.message,
+    }
+    .into()
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "provider answered {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
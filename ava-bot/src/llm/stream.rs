@@ -0,0 +1,95 @@
+use super::UpstreamError;
+use anyhow::{anyhow, Result};
+use eventsource_stream::Eventsource;
+use llm_sdk::{ChatCompletionRequest, FinishReason};
+use serde::Deserialize;
+use std::pin::Pin;
+use tokio_stream::{Stream, StreamExt};
+
+pub type DeltaStream = Pin<Box<dyn Stream<Item = Result<Delta>> + Send>>;
+
+/// A chunk of a streamed completion. The last one tells why the completion
+/// ended, e.g. cut off at the token limit.
+#[derive(Debug)]
+pub struct Delta {
+    pub content: String,
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// Streaming chat completion against the OpenAI compatible endpoint, since
+/// llm-sdk only supports request / response.
+pub(crate) struct ChatStream {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionChunk {
+    choices: Vec<ChatCompletionChunkChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionChunkChoice {
+    delta: ChatCompletionDelta,
+    // a string, reasons llm-sdk doesn't know shouldn't fail the stream
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl ChatStream {
+    pub(crate) fn new(token: impl Into<String>, base_url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.into(),
+            token: token.into(),
+        }
+    }
+
+    /// Send the request with `stream: true` and yield deltas as they arrive.
+    pub(crate) async fn chat_completion(&self, req: &ChatCompletionRequest) -> Result<DeltaStream> {
+        let mut body = serde_json::to_value(req)?;
+        body["stream"] = true.into();
+
+        let res = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .bearer_auth(&self.token)
+            .json(&body)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            return Err(UpstreamError::new(status, &body).into());
+        }
+
+        let stream = res
+            .bytes_stream()
+            .eventsource()
+            .take_while(|event| !matches!(event, Ok(event) if event.data == "[DONE]"))
+            .filter_map(|event| match event {
+                Ok(event) => match serde_json::from_str::<ChatCompletionChunk>(&event.data) {
+                    Ok(chunk) => chunk.choices.into_iter().next().and_then(|choice| {
+                        let delta = Delta {
+                            content: choice.delta.content.unwrap_or_default(),
+                            finish_reason: choice
+                                .finish_reason
+                                .and_then(|reason| serde_json::from_value(reason.into()).ok()),
+                        };
+                        let empty = delta.content.is_empty() && delta.finish_reason.is_none();
+                        (!empty).then_some(Ok(delta))
+                    }),
+                    Err(e) => Some(Err(e.into())),
+                },
+                Err(e) => Some(Err(anyhow!("failed to read completion stream: {e}"))),
+            });
+        Ok(Box::pin(stream))
+    }
+}
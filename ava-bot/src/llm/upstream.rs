@@ -0,0 +1,229 @@
+use super::{LlmBackend, OpenAiBackend, UpstreamError};
+use crate::config::{LlmConfig, RetryConfig, TimeoutConfig};
+use crate::error::ErrorKind;
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use llm_sdk::ChatCompleteModel;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use strum::Display;
+use tracing::warn;
+
+/// The calls made to providers, each with its own timeout.
+#[derive(Debug, Clone, Copy, Display)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum Operation {
+    Transcription,
+    Chat,
+    Speech,
+    Image,
+}
+
+/// A provider of the AI operations: the configured backend, then the ones of
+/// `llm.fallbacks` in order.
+pub(crate) struct Provider {
+    /// base url, for logs
+    pub(crate) name: String,
+    pub(crate) backend: Arc<dyn LlmBackend>,
+    /// chat completion model used with this provider
+    pub(crate) model: ChatCompleteModel,
+}
+
+/// The providers, and how calls to them are timed out and retried.
+pub(crate) struct Upstream {
+    providers: Vec<Provider>,
+    retry: RetryConfig,
+    timeouts: TimeoutConfig,
+}
+
+impl Upstream {
+    pub(crate) fn new(config: &LlmConfig, api_key: &str, backend: impl LlmBackend) -> Self {
+        let mut providers = vec![Provider {
+            name: config.base_url.clone(),
+            backend: Arc::new(backend),
+            model: config.model,
+        }];
+        providers.extend(config.fallbacks.iter().map(|fallback| Provider {
+            name: fallback.base_url.clone(),
+            backend: Arc::new(OpenAiBackend::new(
+                fallback.api_key.as_deref().unwrap_or(api_key),
+                &fallback.base_url,
+            )),
+            model: fallback.model.unwrap_or(config.model),
+        }));
+        Self {
+            providers,
+            retry: config.retry.clone(),
+            timeouts: config.timeouts.clone(),
+        }
+    }
+
+    /// Call `f` with the providers in order until a call succeeds. Transient
+    /// failures are retried with backoff, then the next provider is tried;
+    /// failures of the provider itself, e.g. a rejected key or an unknown
+    /// model, go to the next provider right away. Only failures of the
+    /// request itself are returned at once, another provider would refuse it
+    /// the same. `on_retry(attempt, max)` is told before every retry.
+    pub(crate) async fn call<T>(
+        &self,
+        op: Operation,
+        on_retry: impl Fn(usize, usize),
+        f: impl for<'a> Fn(&'a Provider) -> BoxFuture<'a, Result<T>>,
+    ) -> Result<T> {
+        let attempts = self.retry.max_attempts;
+        let max = self.providers.len() * attempts;
+        let timeout = self.timeout(op);
+        for (i, provider) in self.providers.iter().enumerate() {
+            let last_provider = i + 1 == self.providers.len();
+            for n in 0..attempts {
+                let attempt = i * attempts + n + 1;
+                let err = match tokio::time::timeout(timeout, f(provider)).await {
+                    Ok(Ok(v)) => return Ok(v),
+                    Ok(Err(e)) => e,
+                    Err(elapsed) => anyhow::Error::new(elapsed)
+                        .context(format!("{op} timed out after {}s", timeout.as_secs())),
+                };
+                let failure = Failure::of(&err);
+                let retry_here = failure == Failure::Transient && n + 1 < attempts;
+                if failure == Failure::Request || (!retry_here && last_provider) {
+                    return Err(err);
+                }
+                warn!(
+                    "{op} on {} failed ({attempt}/{max}): {err:#}",
+                    provider.name
+                );
+                if !retry_here {
+                    on_retry((i + 1) * attempts + 1, max);
+                    break;
+                }
+                on_retry(attempt + 1, max);
+                tokio::time::sleep(self.backoff(n)).await;
+            }
+        }
+        unreachable!("the last attempt returns")
+    }
+
+    pub(crate) fn timeout(&self, op: Operation) -> Duration {
+        let secs = match op {
+            Operation::Transcription => self.timeouts.transcription,
+            Operation::Chat => self.timeouts.chat,
+            Operation::Speech => self.timeouts.speech,
+            Operation::Image => self.timeouts.image,
+        };
+        Duration::from_secs(secs)
+    }
+
+    /// Delay before retry `n + 1`, doubling from `initial_backoff_ms` with
+    /// jitter so retries of concurrent jobs spread out.
+    fn backoff(&self, n: usize) -> Duration {
+        let ms = self
+            .retry
+            .initial_backoff_ms
+            .saturating_mul(1 << n.min(16))
+            .min(self.retry.max_backoff_ms);
+        Duration::from_millis(ms / 2 + rand::thread_rng().gen_range(0..=ms / 2))
+    }
+}
+
+/// Why a call failed, as far as trying again is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// timed out, rate limited, or the provider failed or couldn't be
+    /// reached: the same call may succeed in a moment
+    Transient,
+    /// the request is bad or refused for its content, on any provider
+    Request,
+    /// anything else, e.g. the key is rejected or the model unknown: another
+    /// provider may do
+    Provider,
+}
+
+impl Failure {
+    /// Told by the status the provider answered with, see `UpstreamError`,
+    /// or by the provider not answering at all.
+    fn of(err: &anyhow::Error) -> Self {
+        match ErrorKind::of(err) {
+            ErrorKind::UpstreamTimeout | ErrorKind::RateLimited => return Self::Transient,
+            ErrorKind::BadInput | ErrorKind::ContentFilter => return Self::Request,
+            _ => {}
+        }
+        let transient = err.chain().any(|cause| {
+            if let Some(e) = cause.downcast_ref::<UpstreamError>() {
+                return e.status.is_server_error();
+            }
+            // no status, the provider couldn't be reached
+            cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|e| e.is_connect())
+        });
+        if transient {
+            Self::Transient
+        } else {
+            Self::Provider
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::sdk_error;
+    use anyhow::anyhow;
+    use reqwest::StatusCode;
+
+    fn upstream(status: StatusCode, code: Option<&str>) -> anyhow::Error {
+        UpstreamError {
+            status,
+            code: code.map(str::to_string),
+            message: "failed".to_string(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn failures_are_told_by_status() {
+        let failure = |status, code| Failure::of(&upstream(status, code));
+        assert_eq!(
+            failure(StatusCode::TOO_MANY_REQUESTS, None),
+            Failure::Transient
+        );
+        assert_eq!(failure(StatusCode::BAD_GATEWAY, None), Failure::Transient);
+        assert_eq!(failure(StatusCode::BAD_REQUEST, None), Failure::Request);
+        assert_eq!(
+            failure(StatusCode::BAD_REQUEST, Some("content_policy_violation")),
+            Failure::Request
+        );
+        assert_eq!(failure(StatusCode::UNAUTHORIZED, None), Failure::Provider);
+        assert_eq!(failure(StatusCode::NOT_FOUND, None), Failure::Provider);
+        assert_eq!(Failure::of(&anyhow!("unexpected")), Failure::Provider);
+    }
+
+    #[test]
+    fn sdk_errors_get_their_status_from_the_body() {
+        let sdk = |body: &str| sdk_error(anyhow!("API failed: {body}"));
+        let status = |err: anyhow::Error| err.downcast::<UpstreamError>().unwrap().status;
+
+        let err = sdk(
+            r#"{"error": {"message": "slow down", "type": "requests", "code": "rate_limit_exceeded"}}"#,
+        );
+        assert_eq!(status(err), StatusCode::TOO_MANY_REQUESTS);
+        let err =
+            sdk(r#"{"error": {"message": "bad", "type": "invalid_request_error", "code": null}}"#);
+        assert_eq!(status(err), StatusCode::BAD_REQUEST);
+        let err = sdk(
+            r#"{"error": {"message": "who?", "type": "invalid_request_error", "code": "invalid_api_key"}}"#,
+        );
+        assert_eq!(status(err), StatusCode::UNAUTHORIZED);
+        let err = sdk(r#"{"error": {"message": "oops", "type": "server_error"}}"#);
+        assert_eq!(status(err), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // anything else stays as it is
+        assert!(sdk("<html>bad gateway</html>")
+            .downcast_ref::<UpstreamError>()
+            .is_none());
+        assert!(sdk_error(anyhow!("dns error"))
+            .downcast_ref::<UpstreamError>()
+            .is_none());
+    }
+}
@@ -1,16 +1,26 @@
-use anyhow::Result;
-use ava_bot::handlers::{assistant_handler, events_handler, index_page};
-use ava_bot::Args;
+use anyhow::{Context, Result};
+use ava_bot::handlers::{
+    assistant_handler, cancel_job_handler, chat_completions_handler, chat_handler, events_handler,
+    index_page, job_handler, join_handler, login_handler, logout_handler, models_handler,
+    oidc_callback_handler, oidc_login_handler, pair_page, pairing_handler, register_handler,
+    revoke_device_handler, status_handler, voice_settings_handler,
+};
+use ava_bot::{
+    assets_handler, issue_session, mcp_handler, require_api_key, require_session, serve_mcp_stdio,
+    tls, AppState, Args, Config, OpenAiBackend, Store,
+};
 use clap::Parser;
 use mimalloc::MiMalloc;
 use rust_embed::RustEmbed;
-use salvo::prelude::{RequestId, TcpListener};
-use salvo::serve_static::{static_embed, StaticDir};
+use salvo::conn::Acceptor;
+use salvo::prelude::{ForceHttps, RequestId, TcpListener};
+use salvo::serve_static::static_embed;
 use salvo::server::ServerHandle;
-use salvo::{Listener, Router, Server};
+use salvo::{affix_state, Listener, Router, Server, Service};
+use std::time::Duration;
 use time::macros::{format_description, offset};
 use tokio::signal;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::fmt::time::OffsetTime;
 
 #[global_allocator]
@@ -26,35 +36,135 @@ async fn main() -> Result<()> {
         offset!(+08:00:00),
         format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3] [offset_hour sign:mandatory]:[offset_minute]"),
     );
-    tracing_subscriber::fmt()
+    let args = Args::parse();
+    let subscriber = tracing_subscriber::fmt()
         .with_line_number(true)
         .with_max_level(tracing::Level::INFO)
-        .with_timer(timer)
-        .init();
+        .with_timer(timer);
+    if args.mcp_stdio {
+        // stdout carries the protocol
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
 
-    let args = Args::parse();
-    let router = Router::new()
+    let config = Config::load(&args).context("invalid configuration")?;
+    if args.mcp_stdio {
+        let llm = OpenAiBackend::new(config.api_key(), &config.llm.base_url);
+        return serve_mcp_stdio(AppState::new(config, Store::in_memory()?, llm)).await;
+    }
+    let store = Store::open(&config.server.db_path)?;
+    let llm = OpenAiBackend::new(config.api_key(), &config.llm.base_url);
+    let server_config = config.server.clone();
+    let mcp_http = config.mcp.http;
+    let serve_api = !config.api.keys.is_empty();
+    let state = AppState::new(config, store, llm)
+        .connect_mcp_servers()
+        .await;
+    let events = state.events();
+    tokio::spawn(async move { events.run_eviction().await });
+
+    let mut router = Router::new()
         .hoop(RequestId::new())
+        .hoop(affix_state::inject(state))
         .push(Router::with_path("/public/<*path>").get(static_embed::<Public>()))
+        .push(Router::with_path("/assets/<*path>").get(assets_handler))
         .push(
-            Router::with_path("/assets/<*path>")
-                .get(StaticDir::new(["tmp/ava-bot"]).auto_list(true)),
+            Router::new()
+                .hoop(issue_session)
+                .get(index_page)
+                .push(Router::with_path("/pair/<code>").get(pair_page)),
         )
         .push(
             Router::new()
-                .get(index_page)
+                .hoop(require_session)
                 .push(Router::with_path("/events").get(events_handler))
-                .push(Router::with_path("/assistant").post(assistant_handler)),
+                .push(Router::with_path("/assistant").post(assistant_handler))
+                .push(Router::with_path("/chat").post(chat_handler))
+                .push(
+                    Router::with_path("/jobs/<id>")
+                        .get(job_handler)
+                        .push(Router::with_path("cancel").post(cancel_job_handler)),
+                )
+                .push(Router::with_path("/pair").post(join_handler))
+                .push(Router::with_path("/settings/voice").post(voice_settings_handler))
+                .push(
+                    Router::with_path("/devices")
+                        .push(Router::with_path("pair").post(pairing_handler))
+                        .push(Router::with_path("<device_id>/revoke").post(revoke_device_handler)),
+                )
+                .push(
+                    Router::with_path("/auth")
+                        .push(Router::with_path("register").post(register_handler))
+                        .push(Router::with_path("login").post(login_handler))
+                        .push(Router::with_path("logout").post(logout_handler))
+                        .push(Router::with_path("oidc/login").get(oidc_login_handler))
+                        .push(Router::with_path("oidc/callback").get(oidc_callback_handler)),
+                ),
+        );
+    if serve_api {
+        info!("Serving OpenAI compatible api at /v1");
+        router = router.push(
+            Router::with_path("/v1")
+                .hoop(require_api_key)
+                .push(Router::with_path("models").get(models_handler))
+                .push(Router::with_path("chat/completions").post(chat_completions_handler)),
+        );
+        // connection counts tell who's using Ava, only for api clients too
+        router = router.push(
+            Router::with_path("/status")
+                .hoop(require_api_key)
+                .get(status_handler),
+        );
+    }
+    if mcp_http && serve_api {
+        info!("Serving mcp at /mcp");
+        router = router.push(
+            Router::with_path("/mcp")
+                .hoop(require_api_key)
+                .post(mcp_handler),
+        );
+    } else if mcp_http {
+        warn!("mcp.http needs api.keys for clients to sign in with, not serving /mcp");
+    }
+
+    let addr = format!("0.0.0.0:{}", server_config.port);
+    if tls::has_certs(&server_config.cert_path) {
+        if let Some(redirect_port) = server_config.redirect_port {
+            tokio::spawn(redirect_to_https(redirect_port, server_config.port));
+        }
+
+        let initial = tls::load_config(&server_config.cert_path).await?;
+        let configs = tls::reloadable_config(&server_config.cert_path, initial);
+        info!("Listening on https://{}", addr);
+        serve(TcpListener::new(addr).rustls(configs).bind().await, router).await;
+    } else {
+        warn!(
+            "no cert.pem / key.pem found in {}, serving plain http",
+            server_config.cert_path
         );
+        info!("Listening on http://{}", addr);
+        serve(TcpListener::new(addr).bind().await, router).await;
+    }
 
-    let addr = format!("0.0.0.0:{}", args.port);
-    info!("Listening on {}", addr);
-    let server = Server::new(TcpListener::new(addr).bind().await);
+    Ok(())
+}
+
+async fn serve(acceptor: impl Acceptor + Send + 'static, router: Router) {
+    let server = Server::new(acceptor);
     let handle = server.handle();
     tokio::spawn(shutdown_signal(handle));
     server.serve(router).await;
+}
 
-    Ok(())
+/// Plain http listener that redirects every request to the https port.
+async fn redirect_to_https(port: u16, https_port: u16) {
+    let addr = format!("0.0.0.0:{}", port);
+    info!("Redirecting http://{} to https", addr);
+    let service = Service::new(Router::new()).hoop(ForceHttps::new().https_port(https_port));
+    Server::new(TcpListener::new(addr).bind().await)
+        .serve(service)
+        .await;
 }
 
 async fn shutdown_signal(handle: ServerHandle) {
@@ -79,5 +189,5 @@ async fn shutdown_signal(handle: ServerHandle) {
         _ = ctrl_c => info!("ctrl_c signal received"),
         _ = terminate => info!("terminate signal received"),
     }
-    handle.stop_graceful(std::time::Duration::from_secs(10));
+    handle.stop_graceful(Duration::from_secs(10));
 }
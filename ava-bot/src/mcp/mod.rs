@@ -0,0 +1,232 @@
+mod server;
+mod transport;
+
+use crate::config::McpServerConfig;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+use transport::Transport;
+
+pub use server::{mcp_handler, serve_stdio};
+
+/// Latest protocol version spoken, listed first in `PROTOCOL_VERSIONS`.
+pub(crate) const PROTOCOL_VERSION: &str = "2025-06-18";
+pub(crate) const PROTOCOL_VERSIONS: [&str; 3] = [PROTOCOL_VERSION, "2025-03-26", "2024-11-05"];
+
+/// Client of a Model Context Protocol server, spoken as JSON-RPC over the
+/// server's stdio or streamable HTTP endpoint.
+pub(crate) struct McpClient {
+    name: String,
+    transport: Arc<Transport>,
+    timeout: Duration,
+    next_id: AtomicU64,
+}
+
+/// Tells the server to stop working on a request whose caller went away,
+/// e.g. because the user cancelled the turn.
+struct CancelOnDrop {
+    transport: Arc<Transport>,
+    id: u64,
+    armed: bool,
+}
+
+/// A tool as listed by `tools/list`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct McpToolInfo {
+    pub(crate) name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    /// json schema of the arguments
+    pub(crate) input_schema: Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CallToolResult {
+    #[serde(default)]
+    pub(crate) content: Vec<Content>,
+    #[serde(default)]
+    pub(crate) is_error: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Content {
+    Text {
+        text: String,
+    },
+    Image {
+        data: String,
+        #[serde(rename = "mimeType")]
+        mime_type: String,
+    },
+    Audio {
+        data: String,
+        #[serde(rename = "mimeType")]
+        mime_type: String,
+    },
+    Resource {
+        resource: ResourceContents,
+    },
+    ResourceLink {
+        uri: String,
+        name: String,
+        #[serde(rename = "mimeType", default, skip_serializing_if = "Option::is_none")]
+        mime_type: Option<String>,
+    },
+    /// content types of newer protocol versions
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ResourceContents {
+    pub(crate) uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) blob: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListToolsResult {
+    tools: Vec<McpToolInfo>,
+    #[serde(default, rename = "nextCursor")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl McpClient {
+    /// Start (or reach) the server and run the initialize handshake.
+    pub(crate) async fn connect(config: &McpServerConfig) -> Result<Self> {
+        let transport = match (&config.command, &config.url) {
+            (Some(command), None) => Transport::stdio(command, &config.args, &config.env)?,
+            (None, Some(url)) => Transport::http(url, &config.headers)?,
+            _ => bail!("mcp server {} needs either a command or a url", config.name),
+        };
+        let client = Self {
+            name: config.name.clone(),
+            transport: Arc::new(transport),
+            timeout: Duration::from_secs(config.timeout),
+            next_id: AtomicU64::new(1),
+        };
+
+        let ret = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {"name": "ava-bot", "version": env!("CARGO_PKG_VERSION")},
+                }),
+            )
+            .await
+            .with_context(|| format!("failed to initialize mcp server {}", client.name))?;
+        info!(
+            "connected to mcp server {}: {}",
+            client.name, ret["serverInfo"]
+        );
+        client
+            .transport
+            .notify("notifications/initialized", json!({}))
+            .await?;
+        Ok(client)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// All tools of the server, following pagination.
+    pub(crate) async fn list_tools(&self) -> Result<Vec<McpToolInfo>> {
+        let mut tools = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let ret: ListToolsResult =
+                serde_json::from_value(self.request("tools/list", params).await?)?;
+            tools.extend(ret.tools);
+            match ret.next_cursor {
+                Some(next) if !next.is_empty() => cursor = Some(next),
+                _ => return Ok(tools),
+            }
+        }
+    }
+
+    pub(crate) async fn call_tool(&self, name: &str, arguments: Value) -> Result<CallToolResult> {
+        let ret = self
+            .request("tools/call", json!({"name": name, "arguments": arguments}))
+            .await?;
+        Ok(serde_json::from_value(ret)?)
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        let mut guard = CancelOnDrop {
+            transport: self.transport.clone(),
+            id,
+            // the spec forbids cancelling the handshake
+            armed: method != "initialize",
+        };
+        // timing out drops the request, the guard cancels it on the server
+        let ret = tokio::time::timeout(self.timeout, self.transport.request(id, message))
+            .await
+            .map_err(|elapsed| {
+                anyhow::Error::new(elapsed).context(format!(
+                    "mcp server {} timed out on {method} after {}s",
+                    self.name,
+                    self.timeout.as_secs()
+                ))
+            })?;
+        guard.armed = false;
+        let mut response = ret?;
+        if let Some(error) = response.get_mut("error") {
+            let error: RpcError = serde_json::from_value(error.take())?;
+            bail!(
+                "mcp server {} failed on {method}: {} ({})",
+                self.name,
+                error.message,
+                error.code
+            );
+        }
+        Ok(response
+            .get_mut("result")
+            .map(Value::take)
+            .unwrap_or_default())
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.transport.forget(self.id);
+        let (transport, id) = (self.transport.clone(), self.id);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let params = json!({"requestId": id, "reason": "cancelled by the user"});
+                if let Err(e) = transport.notify("notifications/cancelled", params).await {
+                    debug!("failed to cancel mcp request {id}: {e}");
+                }
+            });
+        }
+    }
+}
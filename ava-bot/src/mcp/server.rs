@@ -0,0 +1,275 @@
+use super::{CallToolResult, Content, ResourceContents, PROTOCOL_VERSION, PROTOCOL_VERSIONS};
+use crate::assets::{asset_mime, asset_path};
+use crate::error::{AppError, Lang};
+use crate::events::EventChannel;
+use crate::handlers::{app_state, ChatReplyData};
+use crate::tools::{
+    AnswerTool, DrawImageTool, SpeechTool, ToolContext, ToolRegistry, TranscriptionTool,
+    WriteCodeTool,
+};
+use crate::AppState;
+use anyhow::{anyhow, bail, Result};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use salvo::http::StatusCode;
+use salvo::prelude::Json;
+use salvo::{handler, Depot, Request, Response};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Assets generated for MCP clients are kept under this device id.
+const MCP_DEVICE_ID: &str = "mcp";
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const PARSE_ERROR: i64 = -32700;
+
+/// Ava's own tools (the ones offered to the model, plus speech and
+/// transcription) served to MCP clients.
+pub(crate) struct McpServer {
+    state: AppState,
+    tools: ToolRegistry,
+}
+
+/// Serve MCP over stdin / stdout until stdin closes.
+pub async fn serve_stdio(state: AppState) -> Result<()> {
+    let server = Arc::new(McpServer::new(state));
+    let stdout = Arc::new(Mutex::new(tokio::io::stdout()));
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    info!("serving mcp over stdio");
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (server, stdout) = (server.clone(), stdout.clone());
+        // tool calls take a while, keep reading so requests run concurrently
+        tokio::spawn(async move {
+            let Some(response) = server.handle_line(&line).await else {
+                return;
+            };
+            let mut line = response.to_string();
+            line.push('\n');
+            let mut stdout = stdout.lock().await;
+            if let Err(e) = stdout.write_all(line.as_bytes()).await {
+                warn!("failed to write mcp response: {e}");
+            }
+            let _ = stdout.flush().await;
+        });
+    }
+    Ok(())
+}
+
+/// Streamable HTTP endpoint. Every message is answered with plain json, the
+/// server never opens a stream of its own.
+#[handler]
+pub async fn mcp_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let server = McpServer::new(state);
+
+    let body = req.payload().await?;
+    match server.handle_line(&String::from_utf8_lossy(body)).await {
+        Some(response) => res.render(Json(response)),
+        None => {
+            res.status_code(StatusCode::ACCEPTED);
+        }
+    }
+    Ok(())
+}
+
+impl McpServer {
+    pub(crate) fn new(state: AppState) -> Self {
+        // no voice settings, they're kept per device and MCP clients have none
+        let mut tools = ToolRegistry::empty();
+        tools
+            .register(DrawImageTool)
+            .register(WriteCodeTool)
+            .register(AnswerTool)
+            .register(SpeechTool)
+            .register(TranscriptionTool);
+        Self { state, tools }
+    }
+
+    /// Handle one json-rpc message, returning the response if it was a request.
+    async fn handle_line(&self, line: &str) -> Option<Value> {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(v) => v,
+            Err(e) => return Some(rpc_error(Value::Null, PARSE_ERROR, e.to_string())),
+        };
+        // notifications and responses need no answer
+        let id = message.get("id").cloned()?;
+        let method = message.get("method")?.as_str().unwrap_or_default();
+        let params = message.get("params").cloned().unwrap_or_default();
+
+        let response = match method {
+            "initialize" => Ok(self.initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(self.list_tools()),
+            "tools/call" => self.call_tool(&params).await,
+            "resources/list" => Ok(json!({ "resources": [] })),
+            "resources/read" => self.read_resource(&params).await,
+            _ => return Some(rpc_error(id, METHOD_NOT_FOUND, "method not found")),
+        };
+        Some(match response {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => rpc_error(id, INVALID_PARAMS, format!("{e:#}")),
+        })
+    }
+
+    fn initialize(&self, params: &Value) -> Value {
+        let requested = params["protocolVersion"].as_str().unwrap_or_default();
+        let version = PROTOCOL_VERSIONS
+            .into_iter()
+            .find(|v| *v == requested)
+            .unwrap_or(PROTOCOL_VERSION);
+        json!({
+            "protocolVersion": version,
+            "capabilities": {"tools": {}, "resources": {}},
+            "serverInfo": {"name": "ava-bot", "version": env!("CARGO_PKG_VERSION")},
+        })
+    }
+
+    fn list_tools(&self) -> Value {
+        let tools: Vec<_> = self
+            .tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "inputSchema": tool.input_schema(),
+                })
+            })
+            .collect();
+        json!({ "tools": tools })
+    }
+
+    /// Run a tool. Failures of the tool itself are reported as an error result
+    /// so the calling agent could see what went wrong.
+    async fn call_tool(&self, params: &Value) -> Result<Value> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let tool = self
+            .tools
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown tool {name}"))?;
+        let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+
+        // progress events of the tools have no one to go to
+        let event_sender = EventChannel::new(16);
+        let ctx = ToolContext {
+            state: &self.state,
+            event_sender: &event_sender,
+            owner_id: MCP_DEVICE_ID,
+            device_id: None,
+            audio_format: None,
+            lang: Lang::default(),
+            reply_id: "",
+            history: &[],
+        };
+        let ret = match tool.call(&ctx, &arguments.to_string()).await {
+            Ok(reply) => CallToolResult {
+                content: self.reply_content(reply),
+                is_error: false,
+            },
+            Err(e) => CallToolResult {
+                content: vec![Content::Text {
+                    text: format!("{e:#}"),
+                }],
+                is_error: true,
+            },
+        };
+        Ok(serde_json::to_value(ret)?)
+    }
+
+    fn reply_content(&self, reply: ChatReplyData) -> Vec<Content> {
+        let mut content = vec![];
+        let text = match &reply {
+            ChatReplyData::Speech(v) => v.text().to_string(),
+            ChatReplyData::Image(v) => v.prompt.clone(),
+            ChatReplyData::Markdown(v) => v.source.clone(),
+            ChatReplyData::Refusal(v) => v.message().to_string(),
+            ChatReplyData::VoiceSettings(v) => v.text(),
+        };
+        if !text.is_empty() {
+            content.push(Content::Text { text });
+        }
+        if let Some(url) = reply.asset_url() {
+            // the url carries a token, so the link works without a session
+            let url = url.trim_start_matches('.');
+            let path = url.split('?').next().unwrap_or_default();
+            content.push(Content::ResourceLink {
+                uri: format!("{}{}", self.state.config.public_url(), url),
+                name: path.rsplit('/').next().unwrap_or_default().to_string(),
+                mime_type: asset_mime(path).map(str::to_string),
+            });
+        }
+        content
+    }
+
+    /// Read a generated asset, given its url under `/assets`. Like the url
+    /// itself, it needs the token it carries.
+    async fn read_resource(&self, params: &Value) -> Result<Value> {
+        let uri = params["uri"].as_str().unwrap_or_default();
+        let (name, query) = uri
+            .strip_prefix(&self.state.config.public_url())
+            .and_then(|v| v.strip_prefix("/assets/"))
+            .and_then(|v| v.split_once('?'))
+            .ok_or_else(|| anyhow!("unknown resource {uri}"))?;
+        let token = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="))
+            .unwrap_or_default();
+        if !self.state.assets.verify(name, token) {
+            warn!("reject mcp read of asset {name}");
+            bail!("unknown resource {uri}");
+        }
+        let path = asset_path(name)
+            .filter(|path| path.is_file())
+            .ok_or_else(|| anyhow!("unknown resource {uri}"))?;
+
+        let data = tokio::fs::read(&path).await?;
+        let contents = ResourceContents {
+            uri: uri.to_string(),
+            mime_type: asset_mime(name).map(str::to_string),
+            text: None,
+            blob: Some(BASE64_STANDARD.encode(data)),
+        };
+        Ok(json!({ "contents": [contents] }))
+    }
+}
+
+fn rpc_error(id: Value, code: i64, message: impl Into<String>) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message.into()}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::FakeBackend;
+    use crate::{Config, Store};
+
+    #[test]
+    fn voice_settings_are_not_offered() {
+        let state = AppState::new(
+            Config::default(),
+            Store::in_memory().unwrap(),
+            FakeBackend::default(),
+        );
+        let tools = McpServer::new(state).list_tools();
+        let names: Vec<_> = tools["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"speech"));
+        assert!(!names.contains(&"voice_settings"));
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, bail, Context, Result};
+use dashmap::DashMap;
+use eventsource_stream::Eventsource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{oneshot, Mutex};
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+
+const SESSION_HEADER: &str = "mcp-session-id";
+
+/// How JSON-RPC messages reach the server.
+pub(super) enum Transport {
+    Stdio(StdioTransport),
+    Http(HttpTransport),
+}
+
+/// Newline delimited messages over the stdin / stdout of a child process.
+/// Responses are matched to requests by id, so calls could run concurrently.
+pub(super) struct StdioTransport {
+    stdin: Arc<Mutex<ChildStdin>>,
+    pending: Arc<DashMap<u64, oneshot::Sender<Value>>>,
+    // killed once the client is dropped
+    _child: Child,
+}
+
+/// Streamable HTTP: every message is POSTed to one endpoint, which answers
+/// with plain json or an SSE stream carrying the response.
+pub(super) struct HttpTransport {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    session_id: RwLock<Option<String>>,
+}
+
+impl Transport {
+    pub(super) fn stdio(
+        command: &str,
+        args: &[String],
+        env: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let mut child = Command::new(command)
+            .args(args)
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to spawn {command}"))?;
+        let stdin = Arc::new(Mutex::new(child.stdin.take().expect("stdin is piped")));
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let pending = Arc::new(DashMap::new());
+        tokio::spawn(read_stdio(stdout, stdin.clone(), pending.clone()));
+        Ok(Self::Stdio(StdioTransport {
+            stdin,
+            pending,
+            _child: child,
+        }))
+    }
+
+    pub(super) fn http(url: &str, headers: &HashMap<String, String>) -> Result<Self> {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| Ok((HeaderName::try_from(k)?, HeaderValue::try_from(v)?)))
+            .collect::<Result<HeaderMap>>()
+            .context("invalid mcp server headers")?;
+        Ok(Self::Http(HttpTransport {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+            headers,
+            session_id: RwLock::new(None),
+        }))
+    }
+
+    /// Send the request `message` with `id` and wait for its response.
+    pub(super) async fn request(&self, id: u64, message: Value) -> Result<Value> {
+        match self {
+            Transport::Stdio(t) => {
+                let (tx, rx) = oneshot::channel();
+                t.pending.insert(id, tx);
+                if let Err(e) = write_line(&t.stdin, &message).await {
+                    t.pending.remove(&id);
+                    return Err(e);
+                }
+                rx.await.map_err(|_| anyhow!("mcp server exited"))
+            }
+            Transport::Http(t) => {
+                let res = t.post(&message).await?;
+                let is_sse = res
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.starts_with("text/event-stream"));
+                if !is_sse {
+                    return Ok(res.json().await?);
+                }
+
+                let mut events = res.bytes_stream().eventsource();
+                while let Some(event) = events.next().await {
+                    let event = event.map_err(|e| anyhow!("failed to read mcp stream: {e}"))?;
+                    let Ok(message) = serde_json::from_str::<Value>(&event.data) else {
+                        continue;
+                    };
+                    if message["id"].as_u64() == Some(id) {
+                        return Ok(message);
+                    }
+                }
+                bail!("mcp stream closed before response {id}")
+            }
+        }
+    }
+
+    /// Stop waiting for the response of `id`.
+    pub(super) fn forget(&self, id: u64) {
+        if let Transport::Stdio(t) = self {
+            t.pending.remove(&id);
+        }
+    }
+
+    /// Send a notification, which has no response.
+    pub(super) async fn notify(&self, method: &str, params: Value) -> Result<()> {
+        let message = json!({"jsonrpc": "2.0", "method": method, "params": params});
+        match self {
+            Transport::Stdio(t) => write_line(&t.stdin, &message).await,
+            Transport::Http(t) => t.post(&message).await.map(|_| ()),
+        }
+    }
+}
+
+impl HttpTransport {
+    async fn post(&self, message: &Value) -> Result<reqwest::Response> {
+        let mut req = self
+            .client
+            .post(&self.url)
+            .headers(self.headers.clone())
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(message);
+        if let Some(session_id) = self.session_id.read().unwrap().as_deref() {
+            req = req.header(SESSION_HEADER, session_id);
+        }
+
+        let res = req.send().await?.error_for_status()?;
+        if let Some(session_id) = res
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            *self.session_id.write().unwrap() = Some(session_id.to_string());
+        }
+        Ok(res)
+    }
+}
+
+async fn write_line(stdin: &Mutex<ChildStdin>, message: &Value) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    let mut stdin = stdin.lock().await;
+    stdin.write_all(&line).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+/// Route responses to the waiting requests and answer requests from the server.
+/// Once stdout closes, every pending request fails.
+async fn read_stdio(
+    stdout: ChildStdout,
+    stdin: Arc<Mutex<ChildStdin>>,
+    pending: Arc<DashMap<u64, oneshot::Sender<Value>>>,
+) {
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("failed to read from mcp server: {e}");
+                break;
+            }
+        };
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            debug!("ignore non json output of mcp server: {line}");
+            continue;
+        };
+
+        match (message["id"].as_u64(), message.get("method")) {
+            (Some(id), None) => {
+                if let Some((_, tx)) = pending.remove(&id) {
+                    let _ = tx.send(message);
+                }
+            }
+            (Some(_), Some(method)) => {
+                let reply = if method == "ping" {
+                    json!({"jsonrpc": "2.0", "id": message["id"], "result": {}})
+                } else {
+                    json!({"jsonrpc": "2.0", "id": message["id"], "error": {"code": -32601, "message": "method not found"}})
+                };
+                if let Err(e) = write_line(&stdin, &reply).await {
+                    warn!("failed to answer mcp server: {e}");
+                }
+            }
+            _ => debug!("mcp notification: {line}"),
+        }
+    }
+    pending.clear();
+}
@@ -0,0 +1,138 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use llm_sdk::{AssistantMessage, ChatCompletionMessage, ToolCall, ToolMessage};
+use std::collections::VecDeque;
+
+/// Per-device conversation history, fed back into every completion request
+/// so Ava can follow up on what was said before.
+pub struct Memory {
+    turns: DashMap<String, VecDeque<Turn>>,
+    max_turns: usize,
+    max_tokens: usize,
+}
+
+/// One user utterance together with everything the assistant and tools
+/// produced for it, in the order they were exchanged.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Turn {
+    messages: Vec<ChatCompletionMessage>,
+}
+
+impl Memory {
+    /// Limit how much history is kept per device. A turn is dropped from the
+    /// front once either limit is exceeded; `0` disables memory entirely.
+    pub(crate) fn new(max_turns: usize, max_tokens: usize) -> Self {
+        Self {
+            turns: DashMap::new(),
+            max_turns,
+            max_tokens,
+        }
+    }
+
+    /// Whether anything was remembered of the device since the start.
+    pub(crate) fn contains(&self, device_id: &str) -> bool {
+        self.turns.contains_key(device_id)
+    }
+
+    /// Start the history of the device with `turns`, oldest first, unless
+    /// it has one already.
+    pub(crate) fn seed(&self, device_id: &str, turns: Vec<Turn>) {
+        if let Entry::Vacant(entry) = self.turns.entry(device_id.to_string()) {
+            let mut turns = VecDeque::from(turns);
+            self.trim(&mut turns);
+            entry.insert(turns);
+        }
+    }
+
+    /// Prior messages for the device, oldest first.
+    pub(crate) fn history(&self, device_id: &str) -> Vec<ChatCompletionMessage> {
+        self.turns
+            .get(device_id)
+            .map(|turns| {
+                turns
+                    .iter()
+                    .flat_map(|t| t.messages.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn push(&self, device_id: &str, turn: Turn) {
+        let mut turns = self.turns.entry(device_id.to_string()).or_default();
+        turns.push_back(turn);
+        self.trim(&mut turns);
+    }
+
+    /// Move the history of `from` to `to`, after what `to` already has, e.g.
+    /// when a device signs in and its chats move to the user.
+    pub(crate) fn rename(&self, from: &str, to: &str) {
+        let Some((_, moved)) = self.turns.remove(from) else {
+            return;
+        };
+        let mut turns = self.turns.entry(to.to_string()).or_default();
+        turns.extend(moved);
+        self.trim(&mut turns);
+    }
+
+    fn trim(&self, turns: &mut VecDeque<Turn>) {
+        let mut tokens: usize = turns.iter().map(Turn::estimate_tokens).sum();
+        while turns.len() > self.max_turns || (tokens > self.max_tokens && !turns.is_empty()) {
+            if let Some(t) = turns.pop_front() {
+                tokens -= t.estimate_tokens();
+            }
+        }
+    }
+}
+
+impl Turn {
+    pub(crate) fn new(input: impl Into<String>) -> Self {
+        Self {
+            messages: vec![ChatCompletionMessage::new_user(input.into(), "")],
+        }
+    }
+
+    /// Record a plain text reply from the assistant.
+    pub(crate) fn reply(&mut self, content: impl Into<String>) {
+        self.messages
+            .push(ChatCompletionMessage::Assistant(AssistantMessage {
+                content: Some(content.into()),
+                name: None,
+                tool_calls: vec![],
+            }));
+    }
+
+    /// Record the tool calls the assistant asked for.
+    pub(crate) fn tool_calls(&mut self, calls: &[ToolCall]) {
+        self.messages
+            .push(ChatCompletionMessage::Assistant(AssistantMessage {
+                content: None,
+                name: None,
+                tool_calls: calls.to_vec(),
+            }));
+    }
+
+    /// Record what a tool returned for one of the calls above.
+    pub(crate) fn tool_result(&mut self, call: &ToolCall, content: impl Into<String>) {
+        self.messages.push(ChatCompletionMessage::Tool(ToolMessage {
+            content: content.into(),
+            tool_call_id: call.id.clone(),
+        }));
+    }
+
+    pub(crate) fn messages(&self) -> &[ChatCompletionMessage] {
+        &self.messages
+    }
+
+    // rough estimation (~4 bytes per token), good enough to keep within context
+    fn estimate_tokens(&self) -> usize {
+        self.messages
+            .iter()
+            .map(|m| {
+                serde_json::to_string(m)
+                    .map(|s| s.len())
+                    .unwrap_or_default()
+                    / 4
+            })
+            .sum()
+    }
+}
@@ -0,0 +1,179 @@
+use crate::config::OidcConfig;
+use anyhow::{bail, Context, Result};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+/// Authorization code flow (with PKCE) against an OpenID Connect provider.
+/// The user is identified by the provider's userinfo endpoint, which is
+/// called with the access token fetched straight from the token endpoint.
+pub(crate) struct OidcClient {
+    config: OidcConfig,
+    redirect_url: String,
+    client: reqwest::Client,
+    // fetched on first sign in, so Ava starts while the provider is down
+    discovery: OnceCell<Discovery>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UserInfo {
+    pub(crate) sub: String,
+    #[serde(default)]
+    preferred_username: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+/// What the login request hands to the callback, kept in a cookie.
+pub(crate) struct LoginState {
+    pub(crate) state: String,
+    pub(crate) verifier: String,
+}
+
+impl OidcClient {
+    pub(crate) fn new(config: OidcConfig, redirect_url: impl Into<String>) -> Self {
+        Self {
+            config,
+            redirect_url: redirect_url.into(),
+            client: reqwest::Client::new(),
+            discovery: OnceCell::new(),
+        }
+    }
+
+    /// Label of the sign in button.
+    pub(crate) fn label(&self) -> &str {
+        &self.config.label
+    }
+
+    pub(crate) fn issuer(&self) -> &str {
+        self.config.issuer.trim_end_matches('/')
+    }
+
+    /// Where to send the browser to sign in, plus the state to check in the callback.
+    pub(crate) async fn authorize_url(&self) -> Result<(Url, LoginState)> {
+        let discovery = self.discovery().await?;
+        let login = LoginState {
+            state: random_token(),
+            verifier: random_token(),
+        };
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(login.verifier.as_bytes()));
+        let url = Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("scope", self.config.scopes.as_str()),
+                ("state", login.state.as_str()),
+                ("code_challenge", challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+        Ok((url, login))
+    }
+
+    /// Redeem the code of the callback and fetch who signed in.
+    pub(crate) async fn user_info(&self, code: &str, verifier: &str) -> Result<UserInfo> {
+        let discovery = self.discovery().await?;
+        let token: TokenResponse = self
+            .client
+            .post(&discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("code_verifier", verifier),
+            ])
+            .send()
+            .await?
+            .error_for_status()
+            .context("failed to redeem the authorization code")?
+            .json()
+            .await?;
+
+        let info = self
+            .client
+            .get(&discovery.userinfo_endpoint)
+            .bearer_auth(token.access_token)
+            .send()
+            .await?
+            .error_for_status()
+            .context("failed to fetch user info")?
+            .json()
+            .await?;
+        Ok(info)
+    }
+
+    async fn discovery(&self) -> Result<&Discovery> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer());
+                let discovery: Discovery = self
+                    .client
+                    .get(&url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+                    .with_context(|| format!("failed to discover {url}"))?;
+                if discovery.issuer.trim_end_matches('/') != self.issuer() {
+                    bail!("provider claims to be issuer {}", discovery.issuer);
+                }
+                Ok(discovery)
+            })
+            .await
+    }
+}
+
+impl UserInfo {
+    /// Name shown for a new user.
+    pub(crate) fn display_name(&self) -> &str {
+        self.preferred_username
+            .as_deref()
+            .or(self.name.as_deref())
+            .or(self.email.as_deref())
+            .unwrap_or(&self.sub)
+    }
+}
+
+impl LoginState {
+    pub(crate) fn to_cookie_value(&self) -> String {
+        format!("{}.{}", self.state, self.verifier)
+    }
+
+    pub(crate) fn from_cookie_value(value: &str) -> Option<Self> {
+        let (state, verifier) = value.split_once('.')?;
+        Some(Self {
+            state: state.to_string(),
+            verifier: verifier.to_string(),
+        })
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
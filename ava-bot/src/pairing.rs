@@ -0,0 +1,85 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How long a pairing code can be redeemed.
+pub(crate) const PAIRING_TTL: Duration = Duration::from_secs(5 * 60);
+const CODE_LEN: usize = 8;
+// no 0/O, 1/I, easy to read out and type on a phone
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Short-lived, single use codes letting another device sign in as the user
+/// that created the code.
+pub(crate) struct Pairings {
+    codes: DashMap<String, Pairing>,
+}
+
+struct Pairing {
+    user_id: String,
+    expires: Instant,
+}
+
+impl Pairings {
+    pub(crate) fn new() -> Self {
+        Self {
+            codes: DashMap::new(),
+        }
+    }
+
+    /// A new code for `user_id`.
+    pub(crate) fn create(&self, user_id: &str) -> String {
+        let now = Instant::now();
+        self.codes.retain(|_, pairing| pairing.expires > now);
+
+        let mut rng = rand::thread_rng();
+        let code: String = (0..CODE_LEN)
+            .map(|_| CODE_CHARS[rng.gen_range(0..CODE_CHARS.len())] as char)
+            .collect();
+        self.codes.insert(
+            code.clone(),
+            Pairing {
+                user_id: user_id.to_string(),
+                expires: now + PAIRING_TTL,
+            },
+        );
+        code
+    }
+
+    /// The user of `code`, which can't be redeemed again afterwards.
+    pub(crate) fn redeem(&self, code: &str) -> Option<String> {
+        let code = normalize(code);
+        let (_, pairing) = self.codes.remove(&code)?;
+        (pairing.expires > Instant::now()).then_some(pairing.user_id)
+    }
+}
+
+/// Codes are shown as `ABCD-EFGH`, accept them typed in any case and spacing.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+pub(crate) fn display_code(code: &str) -> String {
+    let (head, tail) = code.split_at(code.len() / 2);
+    format!("{head}-{tail}")
+}
+
+/// QR code of `url` as an inline svg element.
+pub(crate) fn qr_svg(url: &str) -> Result<String> {
+    let image = QrCode::new(url.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+    // drop the xml declaration, the svg goes into an html page
+    Ok(match image.find("<svg") {
+        Some(start) => image[start..].to_string(),
+        None => image,
+    })
+}
@@ -0,0 +1,327 @@
+use crate::error::{AppError, ErrorKind};
+use crate::handlers::app_state;
+use crate::store::User;
+use crate::AppState;
+use anyhow::{anyhow, Context};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use salvo::http::cookie::{Cookie, SameSite};
+use salvo::http::StatusCode;
+use salvo::prelude::Text;
+use salvo::{handler, Depot, FlowCtrl, Request, Response};
+use serde_json::json;
+use sha2::Sha256;
+use std::io::Write;
+use std::path::Path;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const COOKIE_NAME: &str = "device_id";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and verifies device cookies of the form `{device_id}.{signature}`,
+/// signed with HMAC-SHA256 so a client can't pick (or forge) a device id.
+pub(crate) struct Sessions {
+    key: Vec<u8>,
+}
+
+/// The verified device of the request and the user it's signed in as, put
+/// into the `Depot` by the session middleware.
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    pub(crate) device_id: String,
+    pub(crate) user: Option<User>,
+}
+
+impl Sessions {
+    pub(crate) fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// A new device id and the cookie value carrying it.
+    fn issue(&self) -> (String, String) {
+        let device_id = Uuid::new_v4().to_string();
+        let value = format!("{device_id}.{}", self.sign(&device_id));
+        (device_id, value)
+    }
+
+    /// The device id of a cookie value, if the value is well formed and signed by us.
+    pub(crate) fn verify(&self, value: &str) -> Option<String> {
+        let (device_id, signature) = value.split_once('.')?;
+        // only canonical uuids, they end up in file paths
+        let parsed = Uuid::parse_str(device_id).ok()?;
+        if parsed.hyphenated().to_string() != device_id {
+            return None;
+        }
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(device_id).verify_slice(&signature).ok()?;
+        Some(device_id.to_string())
+    }
+
+    fn sign(&self, device_id: &str) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(self.mac(device_id).finalize().into_bytes())
+    }
+
+    fn mac(&self, device_id: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("hmac accepts any key size");
+        mac.update(b"device:");
+        mac.update(device_id.as_bytes());
+        mac
+    }
+}
+
+/// Middleware for pages: verify the device cookie, issuing a new device if
+/// there is none yet or it doesn't verify, e.g. an unsigned cookie of an
+/// older version or one signed with a replaced key.
+#[handler]
+pub async fn issue_session(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let verified = req
+        .cookie(COOKIE_NAME)
+        .and_then(|cookie| state.sessions.verify(cookie.value()));
+    let device_id = match verified {
+        Some(device_id) => device_id,
+        None => {
+            if req.cookie(COOKIE_NAME).is_some() {
+                info!("replace invalid device cookie");
+            }
+            let (device_id, value) = state.sessions.issue();
+            res.add_cookie(session_cookie(value));
+            device_id
+        }
+    };
+    accept(&state, Some(device_id), depot, res, ctrl)
+}
+
+/// Middleware for APIs: reject requests without a valid device cookie with 401.
+#[handler]
+pub async fn require_session(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let device_id = req
+        .cookie(COOKIE_NAME)
+        .and_then(|cookie| state.sessions.verify(cookie.value()));
+    accept(&state, device_id, depot, res, ctrl)
+}
+
+/// Middleware for the OpenAI compatible and MCP endpoints: reject requests
+/// without a bearer token of `api.keys` with 401, in the OpenAI error format.
+#[handler]
+pub async fn require_api_key(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) -> Result<(), AppError> {
+    let state = app_state(depot)?;
+    let token = req
+        .header::<String>("authorization")
+        .and_then(|v| v.strip_prefix("Bearer ").map(|v| v.trim().to_string()));
+    if !token.is_some_and(|token| is_api_key(&state.config.api.keys, &token)) {
+        warn!("reject request with invalid api key");
+        res.status_code(StatusCode::UNAUTHORIZED);
+        let body = json!({"error": {
+            "message": "invalid api key",
+            "type": "ava_error",
+            "code": "invalid_api_key",
+        }});
+        res.render(Text::Json(body.to_string()));
+        ctrl.skip_rest();
+    }
+    Ok(())
+}
+
+/// Whether `token` is one of `keys`, comparing every key in constant time
+/// so the response time doesn't tell how much of a key was right.
+fn is_api_key(keys: &[String], token: &str) -> bool {
+    keys.iter().fold(false, |found, key| {
+        found | bool::from(key.as_bytes().ct_eq(token.as_bytes()))
+    })
+}
+
+fn accept(
+    state: &AppState,
+    device_id: Option<String>,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) -> Result<(), AppError> {
+    match device_id {
+        Some(device_id) => {
+            let user = state.store.device_user(&device_id)?;
+            depot.inject(Session { device_id, user });
+        }
+        None => {
+            warn!("reject request with invalid device cookie");
+            // drop the bad cookie so a reload of the page starts a new device
+            res.remove_cookie(COOKIE_NAME);
+            ctrl.skip_rest();
+            return Err(ErrorKind::Unauthorized
+                .error("invalid session, please reload the page")
+                .into());
+        }
+    }
+    Ok(())
+}
+
+/// The verified session of the request.
+pub(crate) fn session(depot: &Depot) -> anyhow::Result<&Session> {
+    depot
+        .obtain::<Session>()
+        .map_err(|_| anyhow!("session middleware not installed"))
+}
+
+/// Whom chats, events and assets of the request belong to: the signed in
+/// user, or the device itself while anonymous.
+pub(crate) fn owner_id(depot: &Depot) -> anyhow::Result<String> {
+    Ok(session(depot)?.owner_id().to_string())
+}
+
+impl Session {
+    pub(crate) fn owner_id(&self) -> &str {
+        match &self.user {
+            Some(user) => &user.id,
+            None => &self.device_id,
+        }
+    }
+}
+
+/// Name of the device in the device list, from its user agent.
+pub(crate) fn device_label(req: &Request) -> String {
+    let agent = req.header::<String>("user-agent").unwrap_or_default();
+    let find = |names: &[(&str, &'static str)]| {
+        names
+            .iter()
+            .find(|(pattern, _)| agent.contains(pattern))
+            .map(|(_, name)| *name)
+    };
+    // order matters, e.g. Edge and Chrome on Android also claim to be Safari
+    let browser = find(&[
+        ("Edg/", "Edge"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ]);
+    let os = find(&[
+        ("iPhone", "iPhone"),
+        ("iPad", "iPad"),
+        ("Android", "Android"),
+        ("Mac OS", "macOS"),
+        ("Windows", "Windows"),
+        ("Linux", "Linux"),
+    ]);
+    match (browser, os) {
+        (Some(browser), Some(os)) => format!("{browser} on {os}"),
+        (Some(name), None) | (None, Some(name)) => name.to_string(),
+        (None, None) => "Unknown device".to_string(),
+    }
+}
+
+fn session_cookie(value: String) -> Cookie<'static> {
+    Cookie::build((COOKIE_NAME, value))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .permanent()
+        .build()
+}
+
+/// Read the signing key at `path`, creating a random one on first start so
+/// devices stay signed in across restarts.
+pub(crate) fn load_or_create_key(path: impl AsRef<Path>) -> anyhow::Result<String> {
+    let path = path.as_ref();
+    if path.exists() {
+        let key = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read session key {}", path.display()))?;
+        return Ok(key.trim().to_string());
+    }
+
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = BASE64_URL_SAFE_NO_PAD.encode(key);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(key.as_bytes()))
+        .with_context(|| format!("failed to write session key {}", path.display()))?;
+    info!("created session key {}", path.display());
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_cookie_verifies() {
+        let sessions = Sessions::new("secret");
+        let (device_id, value) = sessions.issue();
+        assert_eq!(sessions.verify(&value), Some(device_id));
+    }
+
+    #[test]
+    fn forged_cookies_are_rejected() {
+        let sessions = Sessions::new("secret");
+        let (device_id, value) = sessions.issue();
+        let (_, signature) = value.split_once('.').unwrap();
+
+        // unsigned, as set by older versions
+        assert_eq!(sessions.verify(&device_id), None);
+        // signed with another key
+        let (_, other) = Sessions::new("other").issue();
+        let other_id = other.split_once('.').unwrap().0;
+        assert_eq!(sessions.verify(&format!("{other_id}.{signature}")), None);
+        assert_eq!(sessions.verify(&Sessions::new("other").issue().1), None);
+        // truncated or tampered signature
+        let truncated = &value[..value.len() - 2];
+        assert_eq!(sessions.verify(truncated), None);
+        assert_eq!(sessions.verify(&format!("{device_id}.")), None);
+        assert_eq!(sessions.verify(&format!("{device_id}.!!!")), None);
+    }
+
+    #[test]
+    fn non_canonical_device_ids_are_rejected() {
+        let sessions = Sessions::new("secret");
+        let (device_id, _) = sessions.issue();
+        let sign = |id: &str| format!("{id}.{}", sessions.sign(id));
+
+        // the same uuid, written otherwise
+        assert_eq!(sessions.verify(&sign(&device_id.to_uppercase())), None);
+        assert_eq!(sessions.verify(&sign(&device_id.replace('-', ""))), None);
+        assert_eq!(sessions.verify(&sign(&format!("{{{device_id}}}"))), None);
+        // not a uuid, even if signed
+        assert_eq!(sessions.verify(&sign("../../etc")), None);
+        assert_eq!(sessions.verify(&sign(&device_id)), Some(device_id));
+    }
+
+    #[test]
+    fn api_key_must_match_a_key_exactly() {
+        let keys = vec!["sk-first".to_string(), "sk-second".to_string()];
+        assert!(is_api_key(&keys, "sk-first"));
+        assert!(is_api_key(&keys, "sk-second"));
+        assert!(!is_api_key(&keys, "sk-firs"));
+        assert!(!is_api_key(&keys, "sk-first2"));
+        assert!(!is_api_key(&keys, ""));
+        assert!(!is_api_key(&[], "sk-first"));
+    }
+}
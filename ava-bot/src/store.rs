@@ -0,0 +1,494 @@
+use crate::error::{ErrorInfo, ErrorKind};
+use crate::handlers::{current_datetime, ChatReplyData};
+use crate::jobs::{Job, JobState};
+use crate::voice::VoiceSettings;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+const MAX_HISTORY: usize = 50;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS chats (
+    id TEXT PRIMARY KEY,
+    device_id TEXT NOT NULL,
+    datetime TEXT NOT NULL,
+    input TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS chats_device_id ON chats (device_id);
+CREATE TABLE IF NOT EXISTS replies (
+    id TEXT PRIMARY KEY,
+    chat_id TEXT NOT NULL REFERENCES chats (id),
+    reply_type TEXT NOT NULL,
+    reply TEXT NOT NULL,
+    asset_url TEXT
+);
+CREATE INDEX IF NOT EXISTS replies_chat_id ON replies (chat_id);
+"#;
+
+/// Applied in order, `PRAGMA user_version` counts the ones done. Databases
+/// from before migrations existed have version 0 and already match `SCHEMA`.
+const MIGRATIONS: &[&str] = &[
+    SCHEMA,
+    // chats belong to a user, or to a device until it signs in
+    r#"
+ALTER TABLE chats RENAME COLUMN device_id TO owner_id;
+DROP INDEX chats_device_id;
+CREATE INDEX chats_owner_id ON chats (owner_id);
+CREATE TABLE users (
+    id TEXT PRIMARY KEY,
+    username TEXT UNIQUE,
+    name TEXT NOT NULL,
+    password_hash TEXT,
+    created_at TEXT NOT NULL
+);
+CREATE TABLE identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    PRIMARY KEY (issuer, subject)
+);
+CREATE TABLE devices (
+    device_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    linked_at TEXT NOT NULL
+);
+CREATE INDEX devices_user_id ON devices (user_id);
+"#,
+    // devices are listed to their user, named after their browser
+    r#"
+ALTER TABLE devices ADD COLUMN label TEXT NOT NULL DEFAULT '';
+"#,
+    // assistant requests run as background jobs
+    r#"
+CREATE TABLE jobs (
+    id TEXT PRIMARY KEY,
+    owner_id TEXT NOT NULL,
+    state TEXT NOT NULL,
+    error TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+"#,
+    // failed jobs tell what kind of error stopped them
+    r#"
+ALTER TABLE jobs ADD COLUMN error_code TEXT;
+"#,
+    // voice settings of a device, as json
+    r#"
+CREATE TABLE voice_settings (
+    device_id TEXT PRIMARY KEY,
+    settings TEXT NOT NULL
+);
+"#,
+];
+
+/// Embedded chat history, one row per turn plus one per reply of the turn,
+/// and the user accounts owning it.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub(crate) id: String,
+    /// shown on the page, the username or the name from the OIDC provider
+    pub(crate) name: String,
+}
+
+/// A device signed in as a user.
+#[derive(Debug, Clone)]
+pub(crate) struct Device {
+    pub(crate) device_id: String,
+    /// e.g. "Safari on iPhone"
+    pub(crate) label: String,
+    pub(crate) linked_at: String,
+}
+
+/// A persisted turn, as shown when the index page is reloaded.
+#[derive(Debug, Clone)]
+pub(crate) struct ChatRecord {
+    pub(crate) id: String,
+    pub(crate) datetime: String,
+    pub(crate) input: String,
+    /// (reply id, reply) in the order they were made
+    pub(crate) replies: Vec<(String, ChatReplyData)>,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::new(Connection::open(path)?)
+    }
+
+    /// A store that's gone with the process, for the stdio MCP server, whose
+    /// tools keep nothing, and for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut conn: Connection) -> Result<Self> {
+        migrate(&mut conn)?;
+        // jobs of the previous run died with it
+        conn.execute(
+            "UPDATE jobs SET state = ?1, error = 'interrupted by a restart', error_code = ?2 WHERE state IN (?3, ?4)",
+            params![
+                JobState::Failed.as_ref(),
+                ErrorKind::Internal.as_ref(),
+                JobState::Queued.as_ref(),
+                JobState::Running.as_ref()
+            ],
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub(crate) fn save_input(
+        &self,
+        owner_id: &str,
+        id: &str,
+        datetime: &str,
+        input: &str,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO chats (id, owner_id, datetime, input) VALUES (?1, ?2, ?3, ?4)",
+            params![id, owner_id, datetime, input],
+        )?;
+        Ok(())
+    }
+
+    /// Insert or replace the reply `id` of the turn `chat_id`.
+    pub(crate) fn save_reply(&self, chat_id: &str, id: &str, reply: &ChatReplyData) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO replies (id, chat_id, reply_type, reply, asset_url) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (id) DO UPDATE SET reply_type = ?3, reply = ?4, asset_url = ?5",
+            params![
+                id,
+                chat_id,
+                reply.as_ref(),
+                serde_json::to_string(reply)?,
+                reply.asset_url()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Latest turns of the user (or anonymous device), oldest first.
+    pub(crate) fn history(&self, owner_id: &str) -> Result<Vec<ChatRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, datetime, input FROM chats WHERE owner_id = ?1 ORDER BY rowid DESC LIMIT ?2",
+        )?;
+        let mut records = stmt
+            .query_map(params![owner_id, MAX_HISTORY], |row| {
+                Ok(ChatRecord {
+                    id: row.get(0)?,
+                    datetime: row.get(1)?,
+                    input: row.get(2)?,
+                    replies: vec![],
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        records.reverse();
+
+        let mut stmt =
+            conn.prepare("SELECT id, reply FROM replies WHERE chat_id = ?1 ORDER BY rowid")?;
+        for record in records.iter_mut() {
+            record.replies = stmt
+                .query_map(params![record.id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .filter_map(|row| {
+                    let (id, reply) = row.ok()?;
+                    Some((id, serde_json::from_str(&reply).ok()?))
+                })
+                .collect();
+        }
+        Ok(records)
+    }
+
+    /// Create a user, `username` and `password_hash` are unset for users
+    /// signing in through OIDC only.
+    pub(crate) fn create_user(
+        &self,
+        username: Option<&str>,
+        name: &str,
+        password_hash: Option<&str>,
+    ) -> Result<User> {
+        let conn = self.conn.lock().unwrap();
+        insert_user(&conn, username, name, password_hash)
+    }
+
+    /// The user and password hash of a password login.
+    pub(crate) fn password_user(&self, username: &str) -> Result<Option<(User, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let ret = conn
+            .query_row(
+                "SELECT id, name, password_hash FROM users WHERE username = ?1 AND password_hash IS NOT NULL",
+                params![username],
+                |row| Ok((User { id: row.get(0)?, name: row.get(1)? }, row.get(2)?)),
+            )
+            .optional()?;
+        Ok(ret)
+    }
+
+    /// The user signed in as `subject` at the OIDC `issuer`, created on first
+    /// sign in. Lookup and creation are one transaction, so two first sign
+    /// ins at once don't make two users.
+    pub(crate) fn identity_user(&self, issuer: &str, subject: &str, name: &str) -> Result<User> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let found = tx
+            .query_row(
+                "SELECT users.id, users.name FROM identities JOIN users ON users.id = identities.user_id
+                 WHERE issuer = ?1 AND subject = ?2",
+                params![issuer, subject],
+                |row| Ok(User { id: row.get(0)?, name: row.get(1)? }),
+            )
+            .optional()?;
+        if let Some(user) = found {
+            return Ok(user);
+        }
+
+        let user = insert_user(&tx, None, name, None)?;
+        tx.execute(
+            "INSERT INTO identities (issuer, subject, user_id) VALUES (?1, ?2, ?3)",
+            params![issuer, subject, user.id],
+        )?;
+        tx.commit()?;
+        Ok(user)
+    }
+
+    /// The user the device is signed in as, if any.
+    pub(crate) fn device_user(&self, device_id: &str) -> Result<Option<User>> {
+        let conn = self.conn.lock().unwrap();
+        let ret = conn
+            .query_row(
+                "SELECT users.id, users.name FROM devices JOIN users ON users.id = devices.user_id
+                 WHERE device_id = ?1",
+                params![device_id],
+                |row| {
+                    Ok(User {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(ret)
+    }
+
+    /// Sign the device in as `user_id`. Chats the device made anonymously move
+    /// to the user. Its assets stay where they are, the user's devices may
+    /// read them anyway.
+    pub(crate) fn link_device(&self, device_id: &str, user_id: &str, label: &str) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO devices (device_id, user_id, linked_at, label) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (device_id) DO UPDATE SET user_id = ?2, linked_at = ?3, label = ?4",
+            params![device_id, user_id, current_datetime(), label],
+        )?;
+        tx.execute(
+            "UPDATE chats SET owner_id = ?2 WHERE owner_id = ?1",
+            params![device_id, user_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Devices signed in as the user, most recently linked first.
+    pub(crate) fn user_devices(&self, user_id: &str) -> Result<Vec<Device>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT device_id, label, linked_at FROM devices WHERE user_id = ?1 ORDER BY linked_at DESC",
+        )?;
+        let devices = stmt
+            .query_map(params![user_id], |row| {
+                Ok(Device {
+                    device_id: row.get(0)?,
+                    label: row.get(1)?,
+                    linked_at: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(devices)
+    }
+
+    pub(crate) fn unlink_device(&self, device_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM devices WHERE device_id = ?1",
+            params![device_id],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn voice_settings(&self, device_id: &str) -> Result<Option<VoiceSettings>> {
+        let conn = self.conn.lock().unwrap();
+        let settings: Option<String> = conn
+            .query_row(
+                "SELECT settings FROM voice_settings WHERE device_id = ?1",
+                params![device_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(settings.map(|v| serde_json::from_str(&v)).transpose()?)
+    }
+
+    pub(crate) fn save_voice_settings(
+        &self,
+        device_id: &str,
+        settings: &VoiceSettings,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO voice_settings (device_id, settings) VALUES (?1, ?2)
+             ON CONFLICT (device_id) DO UPDATE SET settings = ?2",
+            params![device_id, serde_json::to_string(settings)?],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn create_job(&self, owner_id: &str) -> Result<Job> {
+        let now = current_datetime();
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            owner_id: owner_id.to_string(),
+            state: JobState::Queued,
+            error: None,
+            error_code: None,
+            created_at: now.clone(),
+            updated_at: now,
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO jobs (id, owner_id, state, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![job.id, job.owner_id, job.state.as_ref(), job.created_at, job.updated_at],
+        )?;
+        Ok(job)
+    }
+
+    pub(crate) fn update_job(
+        &self,
+        id: &str,
+        state: JobState,
+        error: Option<&ErrorInfo>,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE jobs SET state = ?2, error = ?3, error_code = ?4, updated_at = ?5 WHERE id = ?1",
+            params![
+                id,
+                state.as_ref(),
+                error.map(|e| &e.message),
+                error.map(|e| e.code.as_ref()),
+                current_datetime()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn job(&self, id: &str) -> Result<Option<Job>> {
+        let conn = self.conn.lock().unwrap();
+        let ret = conn
+            .query_row(
+                "SELECT id, owner_id, state, error, error_code, created_at, updated_at FROM jobs WHERE id = ?1",
+                params![id],
+                |row| {
+                    let state: String = row.get(2)?;
+                    let error_code: Option<String> = row.get(4)?;
+                    Ok(Job {
+                        id: row.get(0)?,
+                        owner_id: row.get(1)?,
+                        state: state.parse().unwrap_or(JobState::Failed),
+                        error: row.get(3)?,
+                        error_code: error_code
+                            .map(|code| code.parse().unwrap_or(ErrorKind::Internal)),
+                        created_at: row.get(5)?,
+                        updated_at: row.get(6)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(ret)
+    }
+}
+
+fn insert_user(
+    conn: &Connection,
+    username: Option<&str>,
+    name: &str,
+    password_hash: Option<&str>,
+) -> Result<User> {
+    let user = User {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+    };
+    let ret = conn.execute(
+        "INSERT INTO users (id, username, name, password_hash, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![user.id, username, user.name, password_hash, current_datetime()],
+    );
+    match ret {
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            return Err(ErrorKind::BadInput.error("username is already taken"));
+        }
+        ret => ret?,
+    };
+    Ok(user)
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taken_username_is_bad_input() {
+        let store = Store::in_memory().unwrap();
+        store.create_user(Some("ava"), "ava", Some("hash")).unwrap();
+        let err = store
+            .create_user(Some("ava"), "ava", Some("hash"))
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::BadInput);
+        // users without a username don't clash
+        store.create_user(None, "Guest", None).unwrap();
+        store.create_user(None, "Guest", None).unwrap();
+    }
+
+    #[test]
+    fn identity_signs_in_as_the_same_user() {
+        let store = Store::in_memory().unwrap();
+        let first = store
+            .identity_user("https://idp", "alice", "Alice")
+            .unwrap();
+        let again = store
+            .identity_user("https://idp", "alice", "Alice")
+            .unwrap();
+        let other = store
+            .identity_user("https://other", "alice", "Alice")
+            .unwrap();
+        assert_eq!(first.id, again.id);
+        assert_ne!(first.id, other.id);
+    }
+}
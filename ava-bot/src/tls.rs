@@ -0,0 +1,83 @@
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::stream::{self, Stream, StreamExt};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::sign::CertifiedKey;
+use salvo::conn::rustls::{Keycert, RustlsConfig};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Whether `dir` holds a cert / key pair to serve TLS with.
+pub fn has_certs(dir: impl AsRef<Path>) -> bool {
+    let dir = dir.as_ref();
+    dir.join(CERT_FILE).exists() && dir.join(KEY_FILE).exists()
+}
+
+/// Load `cert.pem` and `key.pem` (PEM encoded) from `dir`. They're parsed
+/// here, so a broken pair fails to load instead of failing handshakes.
+pub async fn load_config(dir: impl AsRef<Path>) -> Result<RustlsConfig> {
+    let dir = dir.as_ref();
+    let cert = tokio::fs::read(dir.join(CERT_FILE))
+        .await
+        .with_context(|| format!("failed to read {}", dir.join(CERT_FILE).display()))?;
+    let key = tokio::fs::read(dir.join(KEY_FILE))
+        .await
+        .with_context(|| format!("failed to read {}", dir.join(KEY_FILE).display()))?;
+    check_keycert(&cert, &key)
+        .with_context(|| format!("invalid certificates in {}", dir.display()))?;
+    Ok(RustlsConfig::new(Keycert::new().cert(cert).key(key)))
+}
+
+/// Check that `cert` holds a certificate chain and `key` a private key
+/// rustls can sign with, the way the listener will use them.
+fn check_keycert(cert: &[u8], key: &[u8]) -> Result<()> {
+    let certs = rustls_pemfile::certs(&mut &*cert)
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to parse cert.pem")?;
+    if certs.is_empty() {
+        bail!("no certificate in cert.pem");
+    }
+    let key = rustls_pemfile::private_key(&mut &*key)
+        .context("failed to parse key.pem")?
+        .ok_or_else(|| anyhow!("no private key in key.pem"))?;
+    let signing_key = any_supported_type(&key).context("unsupported private key")?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified
+        .keys_match()
+        .context("key.pem doesn't match the certificate")?;
+    Ok(())
+}
+
+/// Yield `initial` first, then a freshly loaded config every time the process
+/// receives SIGHUP. A config that fails to load keeps the current one in use.
+pub fn reloadable_config(
+    dir: impl Into<PathBuf>,
+    initial: RustlsConfig,
+) -> impl Stream<Item = RustlsConfig> + Send + 'static {
+    let dir = dir.into();
+    stream::once(async move { initial }).chain(reloads(dir))
+}
+
+#[cfg(unix)]
+fn reloads(dir: PathBuf) -> impl Stream<Item = RustlsConfig> + Send + 'static {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    stream::unfold((dir, hangup), |(dir, mut hangup)| async move {
+        loop {
+            hangup.recv().await?;
+            info!("SIGHUP received, reloading certificates");
+            match load_config(&dir).await {
+                Ok(config) => return Some((config, (dir, hangup))),
+                Err(e) => warn!("failed to reload certificates: {e:#}"),
+            }
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn reloads(_dir: PathBuf) -> impl Stream<Item = RustlsConfig> + Send + 'static {
+    stream::pending()
+}
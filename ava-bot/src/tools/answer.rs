@@ -0,0 +1,61 @@
+use super::{Tool, ToolContext};
+use crate::handlers::{chat_completion, speak, ChatReplyData, RefusalResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use llm_sdk::ChatCompletionMessage;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+pub(crate) struct AnswerTool;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct AnswerArgs {
+    /// question or prompt from user
+    pub(crate) prompt: String,
+}
+
+#[async_trait]
+impl Tool for AnswerTool {
+    type Args = AnswerArgs;
+
+    fn name(&self) -> &str {
+        "answer"
+    }
+
+    fn description(&self) -> &str {
+        "Just reply based on the prompt."
+    }
+
+    fn progress(&self) -> &str {
+        "Organizing answer"
+    }
+
+    fn ends_turn(&self) -> bool {
+        true
+    }
+
+    async fn execute(&self, ctx: &ToolContext<'_>, args: AnswerArgs) -> Result<ChatReplyData> {
+        let mut messages = vec![ChatCompletionMessage::new_system(
+            &ctx.state.config.prompts.answer,
+            "Ava",
+        )];
+        messages.extend_from_slice(ctx.history);
+        messages.push(ChatCompletionMessage::new_user(args.prompt, ""));
+        let completion =
+            chat_completion(ctx.state, ctx.event_sender, ctx.reply_id, messages).await?;
+        if completion.text.is_empty() {
+            return Ok(RefusalResult::new(ctx.lang).into());
+        }
+        let output = completion.with_refusal(ctx.lang);
+        let ret = speak(
+            ctx.state,
+            ctx.event_sender,
+            ctx.owner_id,
+            &ctx.voice_settings(),
+            ctx.reply_id,
+            &output,
+        )
+        .await?;
+        Ok(ret.into())
+    }
+}
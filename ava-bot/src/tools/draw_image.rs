@@ -0,0 +1,75 @@
+use super::{DrawImageResult, Tool, ToolContext};
+use crate::handlers::{retrying, ChatReplyData};
+use crate::llm::Operation;
+use crate::{image_path, image_url};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use llm_sdk::{CreateImageRequestBuilder, ImageResponseFormat};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::fs;
+use uuid::Uuid;
+
+pub(crate) struct DrawImageTool;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct DrawImageArgs {
+    /// The revised prompt for creating the image
+    pub(crate) prompt: String,
+}
+
+#[async_trait]
+impl Tool for DrawImageTool {
+    type Args = DrawImageArgs;
+
+    fn name(&self) -> &str {
+        "draw_image"
+    }
+
+    fn description(&self) -> &str {
+        "Draw an image based on the prompt."
+    }
+
+    fn progress(&self) -> &str {
+        "Drawing image"
+    }
+
+    async fn execute(&self, ctx: &ToolContext<'_>, args: DrawImageArgs) -> Result<ChatReplyData> {
+        ctx.reply(DrawImageResult::new("", &args.prompt));
+
+        let req = CreateImageRequestBuilder::default()
+            .prompt(args.prompt)
+            .response_format(ImageResponseFormat::B64Json)
+            .build()?;
+        let mut ret = ctx
+            .state
+            .llm
+            .call(Operation::Image, retrying(ctx.event_sender), |provider| {
+                provider.backend.create_image(req.clone())
+            })
+            .await?;
+        let img = ret
+            .data
+            .pop()
+            .ok_or_else(|| anyhow!("expect at least one data"))?;
+        let data = img
+            .b64_json
+            .ok_or_else(|| anyhow!("expect base64 image data"))?;
+        let data = BASE64_STANDARD.decode(data)?;
+        let uuid = Uuid::new_v4().to_string();
+        let path = image_path(ctx.owner_id, &uuid);
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        fs::write(&path, data).await?;
+        Ok(DrawImageResult::new(
+            image_url(&ctx.state.assets, ctx.owner_id, &uuid),
+            img.revised_prompt,
+        )
+        .into())
+    }
+}
@@ -0,0 +1,167 @@
+use super::{md2html, DynTool, ToolContext, ToolRegistry, WriteCodeResult};
+use crate::config::McpServerConfig;
+use crate::handlers::ChatReplyData;
+use crate::mcp::{CallToolResult, Content, McpClient, McpToolInfo};
+use crate::{image_path, image_url, AssetTokens};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+// the API only accepts [a-zA-Z0-9_-]{1,64} as function names
+const MAX_NAME_LEN: usize = 64;
+
+/// A tool of an MCP server, named `{server}__{tool}` towards the model.
+pub(crate) struct McpTool {
+    client: Arc<McpClient>,
+    info: McpToolInfo,
+    name: String,
+    progress: String,
+    definition: llm_sdk::Tool,
+}
+
+/// Connect to every configured server and register its tools. A server that
+/// fails to start is skipped so Ava stays usable without it, so is a tool
+/// whose name is taken, e.g. by a built-in tool or once cut to length.
+pub(crate) async fn register_mcp_servers(registry: &mut ToolRegistry, servers: &[McpServerConfig]) {
+    for server in servers {
+        match connect(server).await {
+            Ok(tools) => {
+                info!("mcp server {} offers {} tools", server.name, tools.len());
+                for tool in tools {
+                    if registry.get(&tool.name).is_some() {
+                        warn!("skip mcp tool {}, the name is taken", tool.name);
+                        continue;
+                    }
+                    registry.register(tool);
+                }
+            }
+            Err(e) => warn!("skip mcp server {}: {e:#}", server.name),
+        }
+    }
+}
+
+async fn connect(server: &McpServerConfig) -> Result<Vec<McpTool>> {
+    let client = Arc::new(McpClient::connect(server).await?);
+    let tools = client
+        .list_tools()
+        .await
+        .with_context(|| format!("failed to list tools of {}", server.name))?;
+    let tools = tools
+        .into_iter()
+        .filter_map(|info| {
+            let name = info.name.clone();
+            McpTool::new(client.clone(), info)
+                .map_err(|e| warn!("skip mcp tool {name} of {}: {e:#}", server.name))
+                .ok()
+        })
+        .collect();
+    Ok(tools)
+}
+
+impl McpTool {
+    /// Fails if the listed tool doesn't make a tool definition of the model API.
+    fn new(client: Arc<McpClient>, info: McpToolInfo) -> Result<Self> {
+        let name: String = format!("{}__{}", client.name(), info.name)
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+                _ => '_',
+            })
+            .take(MAX_NAME_LEN)
+            .collect();
+        let progress = format!("Calling {} of {}", info.name, client.name());
+        let definition = json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": info.description.as_deref().unwrap_or_default(),
+                "parameters": info.input_schema,
+            }
+        });
+        let definition = serde_json::from_value(definition).context("invalid tool definition")?;
+        Ok(Self {
+            client,
+            info,
+            name,
+            progress,
+            definition,
+        })
+    }
+}
+
+#[async_trait]
+impl DynTool for McpTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        self.info.description.as_deref().unwrap_or_default()
+    }
+
+    fn progress(&self) -> &str {
+        &self.progress
+    }
+
+    fn ends_turn(&self) -> bool {
+        false
+    }
+
+    fn definition(&self) -> llm_sdk::Tool {
+        self.definition.clone()
+    }
+
+    fn input_schema(&self) -> serde_json::Value {
+        self.info.input_schema.clone()
+    }
+
+    async fn call(&self, ctx: &ToolContext<'_>, arguments: &str) -> Result<ChatReplyData> {
+        let arguments = if arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str::<Value>(arguments)?
+        };
+        let ret = self.client.call_tool(&self.info.name, arguments).await?;
+        let md = to_markdown(&ctx.state.assets, ctx.owner_id, ret).await?;
+        Ok(WriteCodeResult::new(md2html(&md), md).into())
+    }
+}
+
+/// Render the content of a tool result as markdown. Images are saved as assets
+/// of the device so the markdown (and the conversation memory) only links them.
+async fn to_markdown(assets: &AssetTokens, owner_id: &str, ret: CallToolResult) -> Result<String> {
+    let mut md = String::new();
+    if ret.is_error {
+        md.push_str("**Tool error**\n\n");
+    }
+    for content in ret.content {
+        match content {
+            Content::Text { text } => md.push_str(&text),
+            Content::Image { data, .. } => {
+                let uuid = Uuid::new_v4().to_string();
+                let path = image_path(owner_id, &uuid);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::write(&path, BASE64_STANDARD.decode(data)?).await?;
+                write!(md, "![image]({})", image_url(assets, owner_id, &uuid))?;
+            }
+            Content::Audio { mime_type, .. } => write!(md, "*({mime_type} audio omitted)*")?,
+            Content::ResourceLink { uri, name, .. } => write!(md, "[{name}]({uri})")?,
+            Content::Resource { resource } => match resource.text {
+                Some(text) => write!(md, "`{}`\n\n```\n{}\n```", resource.uri, text)?,
+                None => write!(md, "<{}>", resource.uri)?,
+            },
+            Content::Unsupported => md.push_str("*(unsupported content omitted)*"),
+        }
+        md.push_str("\n\n");
+    }
+    Ok(md.trim_end().to_string())
+}
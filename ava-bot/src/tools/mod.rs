@@ -1,25 +1,98 @@
+mod answer;
+mod draw_image;
+mod mcp;
+mod speech;
+mod transcription;
+mod voice_settings;
+mod write_code;
+
+use crate::error::Lang;
+use crate::events::EventChannel;
+use crate::handlers::{ChatReplyData, ChatReplyEvent};
+use crate::voice::{AudioFormat, VoiceSettings};
+use crate::{AppState, Config};
+use anyhow::Result;
 use askama::Template;
-use llm_sdk::{ChatCompleteModel, ChatCompletionMessage, ChatCompletionRequest, Tool};
+use async_trait::async_trait;
+use comrak::markdown_to_html_with_plugins;
+use comrak::plugins::syntect::SyntectAdapter;
+use llm_sdk::{ChatCompleteModel, ChatCompletionMessage, ChatCompletionRequest};
 use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumString};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, Display)]
-#[serde(rename_all = "snake_case")]
-#[strum(serialize_all = "snake_case")]
-pub(crate) enum AssistantTool {
-    /// Draw a picture based on user's input
-    DrawImage,
-    /// Write code based on user's input
-    WriteCode,
-    /// Just reply based on user's input
-    Answer,
-}
-
-#[derive(Debug, Clone, Deserialize, JsonSchema)]
-pub(crate) struct DrawImageArgs {
-    /// The revised prompt for creating the image
-    pub(crate) prompt: String,
+
+pub(crate) use answer::AnswerTool;
+pub(crate) use draw_image::DrawImageTool;
+pub(crate) use mcp::register_mcp_servers;
+pub(crate) use speech::SpeechTool;
+pub(crate) use transcription::TranscriptionTool;
+pub(crate) use voice_settings::VoiceSettingsTool;
+pub(crate) use write_code::WriteCodeTool;
+
+/// A function the model could call. The arguments schema is generated from
+/// `Args` and the tool is registered in `ToolRegistry::default()`.
+#[async_trait]
+pub(crate) trait Tool: Send + Sync + 'static {
+    type Args: DeserializeOwned + JsonSchema + Send;
+
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// shown to the user while the tool runs, e.g. "Drawing image"
+    fn progress(&self) -> &str;
+
+    /// whether the reply of this tool completes the turn, otherwise its
+    /// result is fed back to the model for the next step
+    fn ends_turn(&self) -> bool {
+        false
+    }
+
+    async fn execute(&self, ctx: &ToolContext<'_>, args: Self::Args) -> Result<ChatReplyData>;
+}
+
+/// Object safe version of `Tool`, implemented for every tool. Tools without a
+/// static argument type (e.g. ones from MCP servers) implement it directly.
+#[async_trait]
+pub(crate) trait DynTool: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    fn progress(&self) -> &str;
+
+    fn ends_turn(&self) -> bool;
+
+    fn definition(&self) -> llm_sdk::Tool;
+
+    /// json schema of the arguments
+    fn input_schema(&self) -> serde_json::Value;
+
+    /// Parse the json arguments from the model and execute the tool.
+    async fn call(&self, ctx: &ToolContext<'_>, arguments: &str) -> Result<ChatReplyData>;
+}
+
+/// All tools the model could choose from.
+pub(crate) struct ToolRegistry {
+    tools: Vec<Box<dyn DynTool>>,
+}
+
+/// Where and for whom a tool call runs.
+pub(crate) struct ToolContext<'a> {
+    pub(crate) state: &'a AppState,
+    pub(crate) event_sender: &'a EventChannel,
+    /// user (or anonymous device) the turn belongs to, owning the assets
+    pub(crate) owner_id: &'a str,
+    /// device the turn comes from, none for API and MCP clients
+    pub(crate) device_id: Option<&'a str>,
+    /// audio format the client asked for in its `Accept` header
+    pub(crate) audio_format: Option<AudioFormat>,
+    /// language of the messages to the user
+    pub(crate) lang: Lang,
+    /// the reply node the tool renders into
+    pub(crate) reply_id: &'a str,
+    /// conversation before the current turn
+    pub(crate) history: &'a [ChatCompletionMessage],
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -34,40 +107,127 @@ pub(crate) struct DrawImageResult {
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "blocks/markdown.html.j2")]
 pub(crate) struct WriteCodeResult {
-    /// revised prompt
+    /// rendered html
     pub(crate) content: String,
+    /// markdown source, fed back to the model
+    #[serde(default)]
+    pub(crate) source: String,
 }
 
-#[derive(Debug, Clone, Deserialize, JsonSchema)]
-pub(crate) struct WriteCodeArgs {
-    /// The revised prompt for writing the code
-    pub(crate) prompt: String,
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "blocks/voice_settings.html.j2")]
+pub(crate) struct VoiceSettingsResult {
+    /// the settings now in use, e.g. "echo, 0.75x, hd"
+    pub(crate) summary: String,
 }
 
-#[derive(Debug, Clone, Deserialize, JsonSchema)]
-pub(crate) struct AnswerArgs {
-    /// question or prompt from user
-    pub(crate) prompt: String,
+/// Render markdown to html, highlighting code blocks.
+pub(crate) fn md2html(md: &str) -> String {
+    let adapter = SyntectAdapter::new(Some("Solarized (dark)"));
+    let options = comrak::Options::default();
+    let mut plugins = comrak::Plugins::default();
+
+    plugins.render.codefence_syntax_highlighter = Some(&adapter);
+    markdown_to_html_with_plugins(md, &options, &plugins)
 }
 
+/// Let the model pick a tool for the conversation so far (history plus the current turn).
 pub(crate) fn tool_completion_request(
-    input: impl Into<String>,
-    name: &str,
+    config: &Config,
+    tools: &ToolRegistry,
+    model: ChatCompleteModel,
+    conversation: Vec<ChatCompletionMessage>,
 ) -> ChatCompletionRequest {
-    let messages = vec![
-        ChatCompletionMessage::new_system("I can help to identify which tool to use, if no proper tool could be used, I'll directly reply the message with pure text", "Ava"),
-        ChatCompletionMessage::new_user(input.into(), name)
-    ];
-    ChatCompletionRequest::new_with_tools(ChatCompleteModel::default(), messages, all_tools())
+    let mut messages = vec![ChatCompletionMessage::new_system(
+        &config.prompts.tool,
+        "Ava",
+    )];
+    messages.extend(conversation);
+    ChatCompletionRequest::new_with_tools(model, messages, tools.definitions())
+}
+
+#[async_trait]
+impl<T: Tool> DynTool for T {
+    fn name(&self) -> &str {
+        Tool::name(self)
+    }
+
+    fn description(&self) -> &str {
+        Tool::description(self)
+    }
+
+    fn progress(&self) -> &str {
+        Tool::progress(self)
+    }
+
+    fn ends_turn(&self) -> bool {
+        Tool::ends_turn(self)
+    }
+
+    fn definition(&self) -> llm_sdk::Tool {
+        llm_sdk::Tool::new_function::<T::Args>(Tool::name(self), Tool::description(self))
+    }
+
+    fn input_schema(&self) -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(T::Args)).expect("schema should serialize")
+    }
+
+    async fn call(&self, ctx: &ToolContext<'_>, arguments: &str) -> Result<ChatReplyData> {
+        let args = serde_json::from_str(arguments)?;
+        self.execute(ctx, args).await
+    }
 }
 
-// TODO: llm-sdk shall provide fuctionality to generate this code
-fn all_tools() -> Vec<Tool> {
-    vec![
-        Tool::new_function::<DrawImageArgs>("draw_image", "Draw an image based on the prompt."),
-        Tool::new_function::<WriteCodeArgs>("write_code", "Write code based on the prompt."),
-        Tool::new_function::<AnswerArgs>("answer", "Just reply based on the prompt."),
-    ]
+impl ToolRegistry {
+    pub(crate) fn empty() -> Self {
+        Self { tools: vec![] }
+    }
+
+    /// Add a tool, replacing any registered tool of the same name.
+    pub(crate) fn register(&mut self, tool: impl DynTool + 'static) -> &mut Self {
+        self.tools.retain(|t| t.name() != DynTool::name(&tool));
+        self.tools.push(Box::new(tool));
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&dyn DynTool> {
+        self.tools
+            .iter()
+            .find(|t| t.name() == name)
+            .map(|t| t.as_ref())
+    }
+
+    pub(crate) fn definitions(&self) -> Vec<llm_sdk::Tool> {
+        self.tools.iter().map(|t| t.definition()).collect()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &dyn DynTool> {
+        self.tools.iter().map(|t| t.as_ref())
+    }
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register(DrawImageTool)
+            .register(WriteCodeTool)
+            .register(VoiceSettingsTool)
+            .register(AnswerTool);
+        registry
+    }
+}
+
+impl ToolContext<'_> {
+    pub(crate) fn voice_settings(&self) -> VoiceSettings {
+        VoiceSettings::of(self.state, self.device_id).accepting(self.audio_format)
+    }
+
+    /// Update the reply node while the tool is still running.
+    pub(crate) fn reply(&self, data: impl Into<ChatReplyData>) {
+        self.event_sender
+            .send(ChatReplyEvent::new(self.reply_id, data).into());
+    }
 }
 
 impl DrawImageResult {
@@ -80,9 +240,22 @@ impl DrawImageResult {
 }
 
 impl WriteCodeResult {
-    pub(crate) fn new(content: impl Into<String>) -> Self {
+    pub(crate) fn new(content: impl Into<String>, source: impl Into<String>) -> Self {
         Self {
             content: content.into(),
+            source: source.into(),
+        }
+    }
+}
+
+impl VoiceSettingsResult {
+    pub(crate) fn new(summary: impl Into<String>) -> Self {
+        Self {
+            summary: summary.into(),
         }
     }
+
+    pub(crate) fn text(&self) -> String {
+        format!("Voice settings: {}", self.summary)
+    }
 }
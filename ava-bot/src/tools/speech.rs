@@ -0,0 +1,44 @@
+use super::{Tool, ToolContext};
+use crate::handlers::{speech, ChatReplyData};
+use anyhow::Result;
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Only offered to MCP clients, the model speaks through `answer`.
+pub(crate) struct SpeechTool;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct SpeechArgs {
+    /// text to turn into speech
+    pub(crate) text: String,
+}
+
+#[async_trait]
+impl Tool for SpeechTool {
+    type Args = SpeechArgs;
+
+    fn name(&self) -> &str {
+        "speech"
+    }
+
+    fn description(&self) -> &str {
+        "Turn the text into spoken audio."
+    }
+
+    fn progress(&self) -> &str {
+        "Generating speech"
+    }
+
+    async fn execute(&self, ctx: &ToolContext<'_>, args: SpeechArgs) -> Result<ChatReplyData> {
+        Ok(speech(
+            ctx.state,
+            ctx.event_sender,
+            ctx.owner_id,
+            &ctx.voice_settings(),
+            &args.text,
+        )
+        .await?
+        .into())
+    }
+}
@@ -0,0 +1,44 @@
+use super::{Tool, ToolContext};
+use crate::handlers::{transcript, ChatReplyData, SpeechResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Only offered to MCP clients, the model gets user's audio already transcribed.
+pub(crate) struct TranscriptionTool;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct TranscriptionArgs {
+    /// base64 encoded audio, e.g. mp3, wav or webm
+    pub(crate) audio: String,
+}
+
+#[async_trait]
+impl Tool for TranscriptionTool {
+    type Args = TranscriptionArgs;
+
+    fn name(&self) -> &str {
+        "transcription"
+    }
+
+    fn description(&self) -> &str {
+        "Transcribe the audio into text."
+    }
+
+    fn progress(&self) -> &str {
+        "Transcribing audio"
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ToolContext<'_>,
+        args: TranscriptionArgs,
+    ) -> Result<ChatReplyData> {
+        let data = BASE64_STANDARD.decode(args.audio.trim())?;
+        let text = transcript(ctx.state, ctx.event_sender, data).await?;
+        Ok(SpeechResult::new_text_only(text).into())
+    }
+}
@@ -0,0 +1,71 @@
+use super::{Tool, ToolContext, VoiceSettingsResult};
+use crate::error::ErrorKind;
+use crate::handlers::ChatReplyData;
+use crate::voice::{Voice, VoiceModel, VoiceSettings, MAX_SPEED, MIN_SPEED};
+use anyhow::Result;
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+pub(crate) struct VoiceSettingsTool;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct VoiceSettingsArgs {
+    /// The voice to speak with: alloy (neutral), echo (male), fable (male,
+    /// british), onyx (male, deep), nova (female) or shimmer (female, soft)
+    pub(crate) voice: Option<Voice>,
+    /// The speed from 0.25 to 4.0, 1.0 is normal
+    pub(crate) speed: Option<f32>,
+    /// Multiplies the current speed when no speed is given, e.g. 0.8 to
+    /// talk slower or 1.25 to talk faster
+    pub(crate) speed_factor: Option<f32>,
+    /// standard, or hd for better quality
+    pub(crate) model: Option<VoiceModel>,
+}
+
+#[async_trait]
+impl Tool for VoiceSettingsTool {
+    type Args = VoiceSettingsArgs;
+
+    fn name(&self) -> &str {
+        "voice_settings"
+    }
+
+    fn description(&self) -> &str {
+        "Change how the assistant's voice sounds, e.g. when asked to talk slower or use a male voice. Only pass what should change."
+    }
+
+    fn progress(&self) -> &str {
+        "Updating voice settings"
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ToolContext<'_>,
+        args: VoiceSettingsArgs,
+    ) -> Result<ChatReplyData> {
+        let device_id = ctx.device_id.ok_or_else(|| {
+            ErrorKind::BadInput.error("voice settings are only kept for devices using the page")
+        })?;
+        // not `ctx.voice_settings()`, the format the client accepts isn't a setting
+        let mut settings = VoiceSettings::of(ctx.state, Some(device_id));
+        if let Some(voice) = args.voice {
+            settings.voice = voice;
+        }
+        match (args.speed, args.speed_factor) {
+            (Some(speed), _) => settings.speed = speed,
+            (None, Some(factor)) => {
+                let speed = (settings.speed * factor * 100.0).round() / 100.0;
+                settings.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+            }
+            (None, None) => {}
+        }
+        if let Some(model) = args.model {
+            settings.model = model;
+        }
+        settings.validate()?;
+        ctx.state.store.save_voice_settings(device_id, &settings)?;
+
+        Ok(VoiceSettingsResult::new(settings.summary()).into())
+    }
+}
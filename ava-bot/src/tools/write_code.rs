@@ -0,0 +1,48 @@
+use super::{md2html, Tool, ToolContext, WriteCodeResult};
+use crate::handlers::{chat_completion, ChatReplyData, RefusalResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use llm_sdk::ChatCompletionMessage;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+pub(crate) struct WriteCodeTool;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct WriteCodeArgs {
+    /// The revised prompt for writing the code
+    pub(crate) prompt: String,
+}
+
+#[async_trait]
+impl Tool for WriteCodeTool {
+    type Args = WriteCodeArgs;
+
+    fn name(&self) -> &str {
+        "write_code"
+    }
+
+    fn description(&self) -> &str {
+        "Write code based on the prompt."
+    }
+
+    fn progress(&self) -> &str {
+        "Writing code"
+    }
+
+    async fn execute(&self, ctx: &ToolContext<'_>, args: WriteCodeArgs) -> Result<ChatReplyData> {
+        let mut messages = vec![ChatCompletionMessage::new_system(
+            &ctx.state.config.prompts.write_code,
+            "Ava",
+        )];
+        messages.extend_from_slice(ctx.history);
+        messages.push(ChatCompletionMessage::new_user(args.prompt, ""));
+        let completion =
+            chat_completion(ctx.state, ctx.event_sender, ctx.reply_id, messages).await?;
+        if completion.text.is_empty() {
+            return Ok(RefusalResult::new(ctx.lang).into());
+        }
+        let md = completion.with_refusal(ctx.lang);
+        Ok(WriteCodeResult::new(md2html(&md), md).into())
+    }
+}
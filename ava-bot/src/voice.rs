@@ -0,0 +1,243 @@
+use crate::config::SpeechConfig;
+use crate::error::ErrorKind;
+use crate::AppState;
+use anyhow::Result;
+use llm_sdk::{SpeechModel, SpeechResponseFormat, SpeechVoice};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, Display, EnumIter, EnumString, IntoEnumIterator};
+use tracing::warn;
+
+pub(crate) const MIN_SPEED: f32 = 0.25;
+pub(crate) const MAX_SPEED: f32 = 4.0;
+
+/// Voices of the speech API.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    AsRefStr,
+    Display,
+    EnumString,
+    EnumIter,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Voice {
+    /// neutral
+    Alloy,
+    /// male
+    Echo,
+    /// male, british
+    Fable,
+    /// male, deep
+    Onyx,
+    /// female
+    Nova,
+    /// female, soft
+    Shimmer,
+}
+
+/// Quality of the speech, HD sounds better but takes longer.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    AsRefStr,
+    Display,
+    EnumString,
+    EnumIter,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum VoiceModel {
+    Standard,
+    Hd,
+}
+
+/// Encodings of the generated speech. Opus is the smallest, for clients on
+/// slow connections.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    AsRefStr,
+    Display,
+    EnumString,
+    EnumIter,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AudioFormat {
+    #[default]
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+    Wav,
+}
+
+/// How Ava speaks to a device, set on the page or by asking Ava.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VoiceSettings {
+    pub(crate) voice: Voice,
+    /// 1.0 is the normal speed
+    pub(crate) speed: f32,
+    pub(crate) model: VoiceModel,
+    /// none to pick what the client accepts
+    #[serde(default)]
+    pub(crate) format: Option<AudioFormat>,
+}
+
+impl VoiceSettings {
+    /// Settings of the device, the configured ones until it changes them.
+    /// Devices of API and MCP clients have none.
+    pub(crate) fn of(state: &AppState, device_id: Option<&str>) -> Self {
+        let saved = device_id.and_then(|id| match state.store.voice_settings(id) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to load voice settings of {id}: {e}");
+                None
+            }
+        });
+        saved.unwrap_or_else(|| Self::from(&state.config.speech))
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !(MIN_SPEED..=MAX_SPEED).contains(&self.speed) {
+            return Err(ErrorKind::BadInput.error(format!(
+                "speed must be between {MIN_SPEED} and {MAX_SPEED}, got {}",
+                self.speed
+            )));
+        }
+        Ok(())
+    }
+
+    /// The format the client listed in its `Accept` header, unless the
+    /// device picked one.
+    pub(crate) fn accepting(mut self, accepted: Option<AudioFormat>) -> Self {
+        self.format = self.format.or(accepted);
+        self
+    }
+
+    /// e.g. "echo, 0.75x, hd"
+    pub(crate) fn summary(&self) -> String {
+        let mut summary = format!("{}, {}x, {}", self.voice, self.speed, self.model);
+        if let Some(format) = self.format {
+            summary.push_str(&format!(", {format}"));
+        }
+        summary
+    }
+}
+
+impl AudioFormat {
+    pub fn extension(self) -> &'static str {
+        self.as_ref()
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Mp3 => "audio/mpeg",
+            // opus from the speech API comes in an ogg container
+            Self::Opus => "audio/ogg",
+            Self::Aac => "audio/aac",
+            Self::Flac => "audio/flac",
+            Self::Wav => "audio/wav",
+        }
+    }
+
+    pub(crate) fn from_extension(ext: &str) -> Option<Self> {
+        Self::iter().find(|format| format.extension() == ext)
+    }
+
+    /// The audio format the client prefers of those it lists in `accept`,
+    /// e.g. `audio/ogg;q=1, audio/mpeg;q=0.5`. Wildcards tell no preference.
+    pub(crate) fn from_accept(accept: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+        for item in accept.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let format = match parts
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "audio/mpeg" | "audio/mp3" => Self::Mp3,
+                "audio/ogg" | "audio/opus" => Self::Opus,
+                "audio/aac" => Self::Aac,
+                "audio/flac" => Self::Flac,
+                "audio/wav" | "audio/wave" | "audio/x-wav" => Self::Wav,
+                _ => continue,
+            };
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+                best = Some((format, q));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+impl From<&SpeechConfig> for VoiceSettings {
+    fn from(config: &SpeechConfig) -> Self {
+        Self {
+            voice: config.voice,
+            speed: config.speed,
+            model: config.model,
+            format: None,
+        }
+    }
+}
+
+impl From<Voice> for SpeechVoice {
+    fn from(voice: Voice) -> Self {
+        match voice {
+            Voice::Alloy => SpeechVoice::Alloy,
+            Voice::Echo => SpeechVoice::Echo,
+            Voice::Fable => SpeechVoice::Fable,
+            Voice::Onyx => SpeechVoice::Onyx,
+            Voice::Nova => SpeechVoice::Nova,
+            Voice::Shimmer => SpeechVoice::Shimmer,
+        }
+    }
+}
+
+impl From<AudioFormat> for SpeechResponseFormat {
+    fn from(format: AudioFormat) -> Self {
+        match format {
+            AudioFormat::Mp3 => SpeechResponseFormat::Mp3,
+            AudioFormat::Opus => SpeechResponseFormat::Opus,
+            AudioFormat::Aac => SpeechResponseFormat::Aac,
+            AudioFormat::Flac => SpeechResponseFormat::Flac,
+            AudioFormat::Wav => SpeechResponseFormat::Wav,
+        }
+    }
+}
+
+impl From<VoiceModel> for SpeechModel {
+    fn from(model: VoiceModel) -> Self {
+        match model {
+            VoiceModel::Standard => SpeechModel::Tts1,
+            VoiceModel::Hd => SpeechModel::Tts1Hd,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Single sign-on against the mock provider of `examples/mock_idp.rs`: the
+//! login redirects to the provider, whose callback signs the device in.
+
+#[allow(dead_code)]
+#[path = "../examples/mock_idp.rs"]
+mod mock_idp;
+
+use ava_bot::handlers::{index_page, oidc_callback_handler, oidc_login_handler};
+use ava_bot::{issue_session, AppState, Config, OpenAiBackend, Store};
+use salvo::prelude::TcpListener;
+use salvo::test::{ResponseExt, TestClient};
+use salvo::{affix_state, Listener, Router, Server, Service};
+
+const AVA: &str = "http://127.0.0.1:5800";
+
+/// Serve the mock provider on a free port, returning its issuer url.
+async fn start_idp() -> String {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let acceptor = TcpListener::new(format!("127.0.0.1:{port}")).bind().await;
+    tokio::spawn(Server::new(acceptor).serve(mock_idp::router()));
+    format!("http://127.0.0.1:{port}")
+}
+
+fn ava(issuer: &str) -> Service {
+    let config: Config = toml::from_str(&format!(
+        r#"
+[session]
+secret = "test"
+
+[oidc]
+issuer = "{issuer}"
+client_id = "ava"
+client_secret = "secret"
+redirect_url = "{AVA}/auth/oidc/callback"
+"#
+    ))
+    .unwrap();
+    let llm = OpenAiBackend::new("", "http://127.0.0.1:1");
+    let state = AppState::new(config, Store::in_memory().unwrap(), llm);
+    let router = Router::new()
+        .hoop(affix_state::inject(state))
+        .hoop(issue_session)
+        .get(index_page)
+        .push(Router::with_path("auth/oidc/login").get(oidc_login_handler))
+        .push(Router::with_path("auth/oidc/callback").get(oidc_callback_handler));
+    Service::new(router)
+}
+
+/// `name=value` of the cookies the response sets.
+fn set_cookies(res: &salvo::Response) -> Vec<String> {
+    res.headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok()?.split(';').next().map(str::to_string))
+        .collect()
+}
+
+fn location(res: &salvo::Response) -> String {
+    res.headers()["location"].to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn oidc_sign_in_links_the_device_to_the_user() {
+    let issuer = start_idp().await;
+    let ava = ava(&issuer);
+
+    // a new device, sent to the provider
+    let res = TestClient::get(format!("{AVA}/auth/oidc/login"))
+        .send(&ava)
+        .await;
+    assert_eq!(res.status_code.map(|v| v.as_u16()), Some(302));
+    let cookies = set_cookies(&res).join("; ");
+    let authorize = location(&res);
+    assert!(authorize.starts_with(&format!("{issuer}/authorize")));
+
+    // the provider signs in as alice and sends the browser back
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("{authorize}&username=alice"))
+        .send()
+        .await
+        .unwrap();
+    let callback = res.headers()["location"].to_str().unwrap().to_string();
+    assert!(callback.starts_with(&format!("{AVA}/auth/oidc/callback?")));
+
+    // a callback with another state is refused
+    let forged = callback.replace("state=", "state=x");
+    let res = TestClient::get(&forged)
+        .add_header("cookie", &cookies, true)
+        .send(&ava)
+        .await;
+    assert_eq!(res.status_code.map(|v| v.as_u16()), Some(400));
+
+    let res = TestClient::get(&callback)
+        .add_header("cookie", &cookies, true)
+        .send(&ava)
+        .await;
+    assert_eq!(res.status_code.map(|v| v.as_u16()), Some(303));
+    assert_eq!(location(&res), "/");
+
+    // the device is signed in as alice now
+    let mut res = TestClient::get(AVA)
+        .add_header("cookie", &cookies, true)
+        .send(&ava)
+        .await;
+    let page = res.take_string().await.unwrap();
+    assert!(page.contains("alice"));
+}